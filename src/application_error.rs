@@ -3,6 +3,9 @@
 // This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
 // If a copy of the MPL was not distributed with this file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
 #[derive(Debug)]
 pub enum ApplicationError {
     InvalidParameter(String),
@@ -14,11 +17,76 @@ pub enum ApplicationError {
     Zip(zip::result::ZipError),
     Image(image::ImageError),
     BasisUniversal(basis_universal::CompressorErrorCode),
+    Template(handlebars::TemplateError),
+    TemplateRender(handlebars::RenderError),
+    Watch(notify::Error),
+    AssetsFailed(usize),
+
+    /// Describes what was being done when the wrapped error happened, e.g. which file was read.
+    Context(String, Box<ApplicationError>),
+}
+
+impl ApplicationError {
+    /// Formats the error together with all of its sources, e.g. `pack Rock004: reading Rock004.zip: No such file or directory`.
+    pub fn chain(&self) -> String {
+        let mut chain = self.to_string();
+        let mut source = self.source();
+        while let Some(err) = source {
+            chain.push_str(": ");
+            chain.push_str(&err.to_string());
+            source = err.source();
+        }
+        chain
+    }
+}
+
+impl Display for ApplicationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidParameter(parameter) => write!(f, "invalid parameter {:?}", parameter),
+            Self::InvalidMetadata => write!(f, "invalid asset metadata"),
+            Self::InvalidImage(image_path) => write!(f, "unexpected image format in {}", image_path.display()),
+            Self::MetadataParse(_) => write!(f, "invalid JSON"),
+            Self::Network(err) => write!(f, "{}", err),
+            Self::Io(err) => write!(f, "{}", err),
+            Self::Zip(err) => write!(f, "{}", err),
+            Self::Image(err) => write!(f, "{}", err),
+            Self::BasisUniversal(err) => write!(f, "Basis Universal compression failed with {:?}", err),
+            Self::Template(err) => write!(f, "{}", err),
+            Self::TemplateRender(err) => write!(f, "{}", err),
+            Self::Watch(err) => write!(f, "{}", err),
+            Self::AssetsFailed(count) => write!(f, "{} asset(s) failed", count),
+            Self::Context(context, _) => write!(f, "{}", context),
+        }
+    }
+}
+
+// Errors of other crates are displayed as they are, so their own source is the next one in the chain.
+impl Error for ApplicationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::MetadataParse(err) => Some(err),
+            Self::Network(err) => err.source(),
+            Self::Io(err) => err.source(),
+            Self::Zip(err) => err.source(),
+            Self::Image(err) => err.source(),
+            Self::Template(err) => err.source(),
+            Self::TemplateRender(err) => err.source(),
+            Self::Watch(err) => err.source(),
+            Self::Context(_, err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
 }
 
-impl std::fmt::Display for ApplicationError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+/// Adds a description of the current operation to errors, similar to `anyhow::Context`.
+pub trait ErrorContext<T> {
+    fn with_context<C: Into<String>>(self, context: impl FnOnce() -> C) -> Result<T, ApplicationError>;
+}
+
+impl<T, E: Into<ApplicationError>> ErrorContext<T> for Result<T, E> {
+    fn with_context<C: Into<String>>(self, context: impl FnOnce() -> C) -> Result<T, ApplicationError> {
+        self.map_err(|err| ApplicationError::Context(context().into(), Box::new(err.into())))
     }
 }
 
@@ -57,3 +125,21 @@ impl From<basis_universal::CompressorErrorCode> for ApplicationError {
         Self::BasisUniversal(err)
     }
 }
+
+impl From<handlebars::TemplateError> for ApplicationError {
+    fn from(err: handlebars::TemplateError) -> Self {
+        Self::Template(err)
+    }
+}
+
+impl From<handlebars::RenderError> for ApplicationError {
+    fn from(err: handlebars::RenderError) -> Self {
+        Self::TemplateRender(err)
+    }
+}
+
+impl From<notify::Error> for ApplicationError {
+    fn from(err: notify::Error) -> Self {
+        Self::Watch(err)
+    }
+}
@@ -0,0 +1,351 @@
+// Copyright (c) 2021 Kyrylo Bazhenov
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use image::imageops::FilterType;
+use image::*;
+use rayon::iter::*;
+use std::fs::*;
+use std::path::*;
+
+use crate::application_error::*;
+use crate::manifest::*;
+use crate::material::*;
+use crate::mips::{self, MipSettings};
+use crate::pack::{self, MATERIAL_PACK_LAYOUT};
+use crate::unity;
+
+/// Output version of the texture arrays and their layer JSON, bump when they change for the same inputs.
+const OUTPUT_VERSION: u32 = 4;
+
+const KTX2_IDENTIFIER: [u8; 12] = [
+    0xab, b'K', b'T', b'X', b' ', b'2', b'0', 0xbb, b'\r', b'\n', 0x1a, b'\n',
+];
+const VK_FORMAT_R8G8B8A8_UNORM: u32 = 37;
+const VK_FORMAT_R8G8B8A8_SRGB: u32 = 43;
+
+/// Texture arrays are sized independently of the packed materials, every layer is resized to the same size.
+pub struct ArraySettings {
+    pub width: u32,
+    pub height: u32,
+    /// Texture template of the Unity3D export, also writes flipbook images that Unity3D imports as `Texture2DArray`.
+    pub unity_texture_template: Option<PathBuf>,
+}
+
+/// Texture of every array layer, built from the same packed image of each material.
+#[derive(Debug, Clone, Copy)]
+enum ArrayTexture {
+    Albedo,
+    Normal,
+    MaterialPack,
+}
+
+impl ArrayTexture {
+    const ALL: [Self; 3] = [Self::Albedo, Self::Normal, Self::MaterialPack];
+
+    fn suffix(self) -> &'static str {
+        match self {
+            Self::Albedo => "A",
+            Self::Normal => "N",
+            Self::MaterialPack => "MODR",
+        }
+    }
+
+    fn srgb(self) -> bool {
+        matches!(self, Self::Albedo)
+    }
+
+    fn mip_settings(self, material: &serde_json::Value) -> MipSettings {
+        match self {
+            Self::Albedo => MipSettings::albedo(material),
+            Self::Normal => MipSettings::normal(),
+            Self::MaterialPack => MipSettings::linear(),
+        }
+    }
+
+    /// Texel of layers whose material has no such packed image.
+    fn default_texel(self) -> [u8; 4] {
+        match self {
+            Self::Albedo => [255, 255, 255, 255],
+            Self::Normal => [128, 128, 255, 255],
+            Self::MaterialPack => MATERIAL_PACK_LAYOUT.map(|channel| channel.default_byte()),
+        }
+    }
+}
+
+/// Builds the texture array `array_name` from packed materials, in the given order, into `<array_cache_path>/<name>`.
+pub fn generate_texture_array(
+    pack_cache_path: &Path,
+    array_cache_path: &Path,
+    array_name: &str,
+    material_names: &[String],
+    array_settings: &ArraySettings,
+    force_array: bool,
+) -> Result<(), ApplicationError> {
+    create_dir_all(array_cache_path).with_context(|| format!("creating {}", array_cache_path.display()))?;
+
+    log::info!("ARRAY {} layers", material_names.len());
+    let manifest = BuildManifest::load(array_cache_path)?;
+    let result = build_texture_array(
+        &manifest,
+        pack_cache_path,
+        array_cache_path,
+        array_name,
+        material_names,
+        array_settings,
+        force_array,
+    );
+    manifest.save()?;
+    result.map(|_| ())
+}
+
+/// Writes a KTX2 texture array for albedo, normal and `_MODR` images with one layer per material, and `<name>.json`
+/// mapping layer numbers to material names. Layers keep the order of `material_names`.
+pub fn build_texture_array(
+    manifest: &BuildManifest,
+    pack_cache_path: &Path,
+    array_cache_path: &Path,
+    array_name: &str,
+    material_names: &[String],
+    array_settings: &ArraySettings,
+    force_array: bool,
+) -> Result<MaterialOutputs, ApplicationError> {
+    if material_names.is_empty() {
+        return Err(ApplicationError::InvalidParameter(array_name.to_owned()));
+    }
+
+    let mut outputs = MaterialOutputs::new(array_name);
+    let target_path = array_cache_path.join(array_name);
+    create_dir_all(&target_path).with_context(|| format!("creating {}", target_path.display()))?;
+    let temp_file_path = target_path.join("mtldarray.tmp");
+
+    let mut materials = Vec::with_capacity(material_names.len());
+    for material_name in material_names {
+        let material_json = pack_cache_path.join(material_name).join("Material.json");
+        if !material_json.exists() {
+            return Err(ApplicationError::InvalidParameter(material_name.clone()))
+                .with_context(|| format!("finding packed material {}", material_name));
+        }
+        let material: serde_json::Value = serde_json::from_str(
+            &read_to_string(&material_json).with_context(|| format!("reading {}", material_json.display()))?,
+        )
+        .with_context(|| format!("parsing {}", material_json.display()))?;
+        materials.push(material);
+    }
+
+    let common_hasher = || {
+        manifest
+            .hasher("array", OUTPUT_VERSION)
+            .setting("width", array_settings.width)
+            .setting("height", array_settings.height)
+            .setting("materials", material_names.join(","))
+    };
+
+    let index_path = target_path.join(format!("{}.json", array_name));
+    let index_input_hash = common_hasher().finish();
+    let index_outdated = force_array || !manifest.is_up_to_date(&index_path, &index_input_hash);
+    if index_outdated {
+        let index = serde_json::json!({
+            "name": array_name,
+            "width": array_settings.width,
+            "height": array_settings.height,
+            "layers": material_names
+                .iter()
+                .enumerate()
+                .map(|(layer, material_name)| serde_json::json!({ "layer": layer, "material": material_name }))
+                .collect::<Vec<_>>(),
+        });
+        write(&temp_file_path, serde_json::to_string_pretty(&index)?)
+            .with_context(|| format!("writing {}", temp_file_path.display()))?;
+        rename(&temp_file_path, &index_path).with_context(|| format!("writing {}", index_path.display()))?;
+        manifest.record(&index_path, &index_input_hash);
+    }
+    outputs.push(index_path, index_outdated);
+
+    for array_texture in ArrayTexture::ALL {
+        let source_paths = material_names
+            .iter()
+            .map(|material_name| {
+                pack_cache_path
+                    .join(material_name)
+                    .join(format!("{}_{}.png", material_name, array_texture.suffix()))
+            })
+            .collect::<Vec<_>>();
+
+        let mut input_hasher = common_hasher().setting("alpha_test_threshold", mips::ALPHA_TEST_THRESHOLD);
+        for (material_name, source_path) in material_names.iter().zip(&source_paths) {
+            input_hasher = input_hasher
+                .file(&pack_cache_path.join(material_name).join("Material.json"))?
+                .file(source_path)?;
+        }
+        let ktx2_input_hash = input_hasher.clone().finish();
+        let unity_input_hash = match &array_settings.unity_texture_template {
+            Some(texture_template) => Some(input_hasher.file(texture_template)?.finish()),
+            None => None,
+        };
+
+        let ktx2_path = target_path.join(format!("{}_{}.ktx2", array_name, array_texture.suffix()));
+        let ktx2_outdated = force_array || !manifest.is_up_to_date(&ktx2_path, &ktx2_input_hash);
+        let unity_path = target_path.join(format!("{}_{}.png", array_name, array_texture.suffix()));
+        let unity_outdated = unity_input_hash
+            .as_ref()
+            .is_some_and(|input_hash| force_array || !manifest.is_up_to_date(&unity_path, input_hash));
+
+        if ktx2_outdated || unity_outdated {
+            log::debug!("ARRAY {}_{}", array_name, array_texture.suffix());
+
+            let layers = source_paths
+                .par_iter()
+                .map(|source_path| load_layer(source_path, array_texture, array_settings))
+                .collect::<Result<Vec<_>, ApplicationError>>()?;
+
+            if ktx2_outdated {
+                let layer_mips = layers
+                    .par_iter()
+                    .zip(&materials)
+                    .map(|(layer, material)| mips::generate_mips(layer, array_texture.mip_settings(material)))
+                    .collect::<Vec<_>>();
+                write(&temp_file_path, encode_ktx2(&layer_mips, array_texture.srgb()))
+                    .with_context(|| format!("writing {}", temp_file_path.display()))?;
+                rename(&temp_file_path, &ktx2_path).with_context(|| format!("writing {}", ktx2_path.display()))?;
+                manifest.record(&ktx2_path, &ktx2_input_hash);
+            }
+
+            if let (Some(texture_template), Some(unity_input_hash)) = (
+                &array_settings.unity_texture_template,
+                unity_input_hash.as_ref().filter(|_| unity_outdated),
+            ) {
+                let alpha_test = materials
+                    .iter()
+                    .any(|material| array_texture.mip_settings(material).alpha_test.is_some());
+                unity::write_array_texture(
+                    &layers,
+                    &unity_path,
+                    texture_template,
+                    array_texture.srgb(),
+                    matches!(array_texture, ArrayTexture::Normal),
+                    alpha_test,
+                )?;
+                manifest.record(&unity_path, unity_input_hash);
+                outputs.push(unity_path.with_extension("png.meta"), true);
+            }
+        }
+        outputs.push(ktx2_path, ktx2_outdated);
+        if unity_input_hash.is_some() {
+            outputs.push(unity_path, unity_outdated);
+        }
+    }
+
+    Ok(outputs)
+}
+
+/// Loads one packed image as an array layer, resized to the array size.
+fn load_layer(
+    source_path: &Path,
+    array_texture: ArrayTexture,
+    array_settings: &ArraySettings,
+) -> Result<RgbaImage, ApplicationError> {
+    let (width, height) = (array_settings.width, array_settings.height);
+    if !source_path.exists() {
+        return Ok(RgbaImage::from_pixel(
+            width,
+            height,
+            Rgba(array_texture.default_texel()),
+        ));
+    }
+
+    let image = pack::load_png(source_path)?.to_rgba8();
+    if image.dimensions() == (width, height) {
+        Ok(image)
+    } else {
+        Ok(imageops::resize(&image, width, height, FilterType::Lanczos3))
+    }
+}
+
+/// Encodes uncompressed RGBA8 array layers with their mip chains as KTX2, every layer must have the same mip sizes.
+fn encode_ktx2(layer_mips: &[Vec<RgbaImage>], srgb: bool) -> Vec<u8> {
+    let base_level = &layer_mips[0][0];
+    let level_count = layer_mips[0].len();
+
+    // Header, index, level index and a basic data format descriptor with four 8 bit samples.
+    let level_index_offset = 12 + 9 * 4 + 4 * 4 + 2 * 8;
+    let dfd_offset = level_index_offset + level_count * 3 * 8;
+    let dfd_length = 4 + 24 + 4 * 16;
+
+    let mut ktx2 = Vec::new();
+    ktx2.extend_from_slice(&KTX2_IDENTIFIER);
+    for value in [
+        if srgb {
+            VK_FORMAT_R8G8B8A8_SRGB
+        } else {
+            VK_FORMAT_R8G8B8A8_UNORM
+        },
+        1, // typeSize
+        base_level.width(),
+        base_level.height(),
+        0, // pixelDepth
+        layer_mips.len() as u32,
+        1, // faceCount
+        level_count as u32,
+        0, // supercompressionScheme
+        dfd_offset as u32,
+        dfd_length as u32,
+        0, // kvdByteOffset
+        0, // kvdByteLength
+    ] {
+        ktx2.extend_from_slice(&value.to_le_bytes());
+    }
+    ktx2.extend_from_slice(&0u64.to_le_bytes()); // sgdByteOffset
+    ktx2.extend_from_slice(&0u64.to_le_bytes()); // sgdByteLength
+
+    // Level data is stored from the smallest level to the largest, every level holds all layers.
+    let level_lengths = (0..level_count)
+        .map(|level_index| {
+            layer_mips
+                .iter()
+                .map(|mips| mips[level_index].as_raw().len())
+                .sum::<usize>()
+        })
+        .collect::<Vec<_>>();
+    let mut level_offset = dfd_offset + dfd_length;
+    let mut level_offsets = vec![0; level_count];
+    for level_index in (0..level_count).rev() {
+        level_offsets[level_index] = level_offset;
+        level_offset += level_lengths[level_index];
+    }
+    for (offset, length) in level_offsets.iter().zip(&level_lengths) {
+        for value in [*offset, *length, *length] {
+            ktx2.extend_from_slice(&(value as u64).to_le_bytes());
+        }
+    }
+
+    ktx2.extend_from_slice(&(dfd_length as u32).to_le_bytes());
+    ktx2.extend_from_slice(&0u32.to_le_bytes()); // vendorId and descriptorType
+    ktx2.extend_from_slice(&2u16.to_le_bytes()); // versionNumber
+    ktx2.extend_from_slice(&((dfd_length - 4) as u16).to_le_bytes());
+    // RGBSDA color model, BT.709 primaries, sRGB or linear transfer function, straight alpha.
+    ktx2.extend_from_slice(&[1, 1, if srgb { 2 } else { 1 }, 0]);
+    ktx2.extend_from_slice(&[0, 0, 0, 0]); // texelBlockDimension
+    ktx2.extend_from_slice(&[4, 0, 0, 0, 0, 0, 0, 0]); // bytesPlane
+    for (channel_index, channel_id) in [0u8, 1, 2, 15].into_iter().enumerate() {
+        ktx2.extend_from_slice(&(channel_index as u16 * 8).to_le_bytes()); // bitOffset
+        ktx2.push(7); // bitLength - 1
+                      // Alpha of sRGB formats is linear.
+        ktx2.push(if srgb && channel_id == 15 {
+            channel_id | 0x10
+        } else {
+            channel_id
+        });
+        ktx2.extend_from_slice(&[0, 0, 0, 0]); // samplePosition
+        ktx2.extend_from_slice(&0u32.to_le_bytes()); // sampleLower
+        ktx2.extend_from_slice(&255u32.to_le_bytes()); // sampleUpper
+    }
+
+    for level_index in (0..level_count).rev() {
+        for mips in layer_mips {
+            ktx2.extend_from_slice(mips[level_index].as_raw());
+        }
+    }
+    ktx2
+}
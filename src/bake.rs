@@ -0,0 +1,137 @@
+// Copyright (c) 2021 Kyrylo Bazhenov
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use image::*;
+use std::str::FromStr;
+
+use crate::application_error::*;
+use crate::pack::NormalMapType;
+
+/// Texture width the normal strength is defined at, so generated normals look the same at every pack target size.
+const NORMAL_STRENGTH_REFERENCE_WIDTH: f32 = 1024.0;
+
+/// Derivative kernel used to generate normal maps, Scharr is more rotation invariant and slightly sharper.
+#[derive(Debug, Clone, Copy)]
+pub enum NormalKernel {
+    Sobel,
+    Scharr,
+}
+
+impl FromStr for NormalKernel {
+    type Err = ApplicationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Sobel" => Ok(Self::Sobel),
+            "Scharr" => Ok(Self::Scharr),
+            _ => Err(ApplicationError::InvalidParameter(s.to_string())),
+        }
+    }
+}
+
+impl NormalKernel {
+    /// Weights of the center row and the two outer rows, normalized so a unit slope gives a unit derivative.
+    fn weights(self) -> (f32, f32) {
+        match self {
+            Self::Sobel => (2.0 / 8.0, 1.0 / 8.0),
+            Self::Scharr => (10.0 / 32.0, 3.0 / 32.0),
+        }
+    }
+}
+
+/// Displacement in the 0..1 range. Materials tile, so reads outside the image wrap around.
+pub struct HeightMap {
+    width: u32,
+    height: u32,
+    values: Vec<f32>,
+}
+
+impl HeightMap {
+    pub fn new(image: &DynamicImage) -> Self {
+        let image = image.to_luma16();
+        Self {
+            width: image.width(),
+            height: image.height(),
+            values: image.pixels().map(|f| f[0] as f32 / 65535.0).collect(),
+        }
+    }
+
+    pub fn get(&self, x: i64, y: i64) -> f32 {
+        let x = x.rem_euclid(self.width as i64) as usize;
+        let y = y.rem_euclid(self.height as i64) as usize;
+        self.values[y * self.width as usize + x]
+    }
+}
+
+/// Generates a tangent-space normal map from a height map, `strength` scales the slopes.
+pub fn normal_from_height(
+    height_map: &HeightMap,
+    strength: f32,
+    kernel: NormalKernel,
+    normal_map_type: NormalMapType,
+) -> RgbImage {
+    let (center_weight, outer_weight) = kernel.weights();
+    let slope_scale = strength * height_map.width as f32 / NORMAL_STRENGTH_REFERENCE_WIDTH;
+
+    RgbImage::from_fn(height_map.width, height_map.height, |x, y| {
+        let (x, y) = (x as i64, y as i64);
+        let h = |dx: i64, dy: i64| height_map.get(x + dx, y + dy);
+
+        let slope_x = center_weight * (h(1, 0) - h(-1, 0)) + outer_weight * (h(1, -1) - h(-1, -1) + h(1, 1) - h(-1, 1));
+        let slope_y = center_weight * (h(0, 1) - h(0, -1)) + outer_weight * (h(-1, 1) - h(-1, -1) + h(1, 1) - h(1, -1));
+
+        // Image rows go down, OpenGL normal maps point green up and Direct3D normal maps point green down.
+        let normal_y = match normal_map_type {
+            NormalMapType::OpenGL => slope_y,
+            NormalMapType::Direct3D => -slope_y,
+        };
+        let normal = [-slope_x * slope_scale, normal_y * slope_scale, 1.0];
+        let length = normal.iter().map(|f| f * f).sum::<f32>().sqrt();
+
+        Rgb(normal.map(|f| ((f / length * 0.5 + 0.5) * 255.0).round() as u8))
+    })
+}
+
+/// Directions the horizon is searched in for every texel.
+const AO_DIRECTIONS: usize = 8;
+
+/// Height samples along every direction, spread evenly up to the radius.
+const AO_STEPS: usize = 8;
+
+/// Bakes ambient occlusion from a height map by searching the horizon around every texel.
+///
+/// `strength` scales heights like the normal strength does, `radius` is the search distance in texels of a 1024 pixel
+/// wide texture. Both are scaled with the width so the result doesn't depend on the texture size.
+pub fn ao_from_height(height_map: &HeightMap, strength: f32, radius: f32) -> GrayImage {
+    let width_scale = height_map.width as f32 / NORMAL_STRENGTH_REFERENCE_WIDTH;
+    let slope_scale = strength * width_scale;
+    let radius = (radius * width_scale).max(1.0);
+
+    let directions: [(f32, f32); AO_DIRECTIONS] = std::array::from_fn(|direction_index| {
+        let angle = direction_index as f32 / AO_DIRECTIONS as f32 * std::f32::consts::TAU;
+        (angle.cos(), angle.sin())
+    });
+
+    GrayImage::from_fn(height_map.width, height_map.height, |x, y| {
+        let center_height = height_map.get(x as i64, y as i64);
+
+        let mut occlusion = 0.0;
+        for (direction_x, direction_y) in directions {
+            // Slope to the highest point seen from this texel, the sine of its elevation is the occluded part.
+            let mut horizon_slope = 0.0f32;
+            for step in 1..=AO_STEPS {
+                let distance = radius * step as f32 / AO_STEPS as f32;
+                let sample_height = height_map.get(
+                    (x as f32 + direction_x * distance).round() as i64,
+                    (y as f32 + direction_y * distance).round() as i64,
+                );
+                horizon_slope = horizon_slope.max((sample_height - center_height) * slope_scale / distance);
+            }
+            occlusion += horizon_slope / (1.0 + horizon_slope * horizon_slope).sqrt();
+        }
+
+        Luma([((1.0 - occlusion / AO_DIRECTIONS as f32) * 255.0).round() as u8])
+    })
+}
@@ -3,77 +3,202 @@
 // This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
 // If a copy of the MPL was not distributed with this file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use basis_universal::sys::UastcPackFlags_PackUASTCLevelSlower;
 use basis_universal::*;
 use image::*;
 use rayon::iter::*;
+use std::collections::BTreeSet;
 use std::fs::*;
-use std::io::BufReader;
 use std::path::*;
 
 use crate::application_error::*;
+use crate::failures::*;
+use crate::logging::*;
+use crate::manifest::*;
+use crate::material::*;
+use crate::mips::{self, MipSettings};
+use crate::pack;
+
+/// Output version of compressed Basis Universal textures, bump when they change for the same inputs.
+const OUTPUT_VERSION: u32 = 2;
+
+/// How packed images are compressed. Images are always compressed to UASTC 4x4 with mipmaps.
+pub struct CompressSettings {
+    /// UASTC quality level from 0, fastest, to 4, slowest and best.
+    pub uastc_level: u32,
+    /// Rate distortion optimization quality, lower values compress better with zstd or LZ4 but lose more quality, 0
+    /// disables rate distortion optimization.
+    pub rdo_quality: f32,
+}
 
 pub fn compress_basisu(
     pack_cache_path: &Path,
     force_basisu: bool,
     basisu_single_threaded: bool,
     basisu_cache_path: &Path,
+    compress_settings: &CompressSettings,
+    only_materials: Option<&BTreeSet<String>>,
+    failures: &FailureLog,
 ) -> Result<(), ApplicationError> {
-    create_dir_all(basisu_cache_path)?;
+    create_dir_all(basisu_cache_path).with_context(|| format!("creating {}", basisu_cache_path.display()))?;
 
-    let directory_contents = read_dir(pack_cache_path)?
-        .filter_map(|f| f.ok())
-        .map(|f| f.path())
-        .filter(|f| f.is_file());
+    let material_paths = material_paths(pack_cache_path, only_materials)?;
+    let manifest = BuildManifest::load(basisu_cache_path)?;
+    let result = compress_all(
+        &manifest,
+        failures,
+        &material_paths,
+        force_basisu,
+        basisu_single_threaded,
+        basisu_cache_path,
+        compress_settings,
+    );
+    manifest.save()?;
+    result
+}
+
+fn compress_all(
+    manifest: &BuildManifest,
+    failures: &FailureLog,
+    material_paths: &[PathBuf],
+    force_basisu: bool,
+    basisu_single_threaded: bool,
+    basisu_cache_path: &Path,
+    compress_settings: &CompressSettings,
+) -> Result<(), ApplicationError> {
+    log::info!("BASISU {} materials", material_paths.len());
+    let progress = StageProgress::new("basisu", material_paths.len() as u64);
+
+    let compress_packed_material = |material_path: &PathBuf| {
+        let material_name = material_path.file_name().unwrap_or_default().to_string_lossy();
+        let result = failures.handle(
+            "basisu",
+            &material_name,
+            compress_material(
+                manifest,
+                material_path,
+                basisu_cache_path,
+                compress_settings,
+                force_basisu,
+            )
+            .map(|_| ()),
+        );
+        progress.inc(&material_name);
+        result
+    };
 
     if basisu_single_threaded {
-        let temp_file_path = basisu_cache_path.join("mtldbasisu.tmp");
-        for material_path in directory_contents {
-            compress_single_material(&temp_file_path, &material_path, force_basisu, basisu_cache_path)?;
+        for material_path in material_paths {
+            compress_packed_material(material_path)?;
         }
     } else {
-        directory_contents
-            .collect::<Vec<PathBuf>>()
+        material_paths
             .par_iter()
-            .map(|material_path| {
-                let temp_file_path =
-                    basisu_cache_path.join(format!("mtldbasisu{}.tmp", rayon::current_thread_index().unwrap_or(0)));
-                compress_single_material(&temp_file_path, material_path, force_basisu, basisu_cache_path)
-            })
+            .map(compress_packed_material)
             .collect::<Result<Vec<_>, ApplicationError>>()?;
     }
 
+    progress.finish();
+
     Ok(())
 }
 
-fn compress_single_material(
-    temp_file_path: &Path,
+/// Returns true when any compressed file of a packed material was built from other inputs than the current ones.
+pub fn is_outdated(
+    manifest: &BuildManifest,
     material_path: &Path,
-    force_basisu: bool,
     basisu_cache_path: &Path,
-) -> Result<(), ApplicationError> {
+    compress_settings: &CompressSettings,
+) -> Result<bool, ApplicationError> {
+    let material_name = material_path.file_name().unwrap_or_default().to_string_lossy();
+    let material_json = material_path.join("Material.json");
+    let target_path = basisu_cache_path.join(material_name.as_ref());
+
+    let target_material_json = target_path.join("Material.json");
+    if manifest.is_recorded(&target_material_json)
+        && !manifest.is_up_to_date(
+            &target_material_json,
+            &manifest.hasher("basisu", OUTPUT_VERSION).file(&material_json)?.finish(),
+        )
+    {
+        return Ok(true);
+    }
+
+    for suffix in ["A", "N", "MODR"] {
+        let source_path = material_path.join(format!("{}_{}.png", material_name, suffix));
+        let target_path = target_path.join(format!("{}_{}.basisu", material_name, suffix));
+        if manifest.is_recorded(&target_path)
+            && !manifest.is_up_to_date(
+                &target_path,
+                &image_input_hash(manifest, &source_path, &material_json, compress_settings)?,
+            )
+        {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+fn image_input_hash(
+    manifest: &BuildManifest,
+    source_path: &Path,
+    material_json: &Path,
+    compress_settings: &CompressSettings,
+) -> Result<String, ApplicationError> {
+    Ok(manifest
+        .hasher("basisu", OUTPUT_VERSION)
+        .file(source_path)?
+        .file(material_json)?
+        .setting("uastc_level", compress_settings.uastc_level)
+        .setting("rdo_quality", compress_settings.rdo_quality)
+        .setting("alpha_test_threshold", mips::ALPHA_TEST_THRESHOLD)
+        .finish())
+}
+
+/// Compresses the packed images of one material folder in the pack cache into `<basisu_cache_path>/<name>`, skipping
+/// compressed files that are up to date.
+pub fn compress_material(
+    manifest: &BuildManifest,
+    material_path: &Path,
+    basisu_cache_path: &Path,
+    compress_settings: &CompressSettings,
+    force_basisu: bool,
+) -> Result<MaterialOutputs, ApplicationError> {
+    let mut outputs = MaterialOutputs::new(material_path.file_name().unwrap_or_default().to_string_lossy());
     if let Some(material_name) = material_path.file_stem().and_then(|p| p.to_str()) {
         let material_json = material_path.join("Material.json");
         if material_json.exists() {
-            println!("BASISU {:?}", material_name);
+            log::debug!("BASISU {:?}", material_name);
 
-            let material: serde_json::Value = serde_json::from_str(&read_to_string(&material_json)?)?;
+            let material: serde_json::Value = serde_json::from_str(
+                &read_to_string(&material_json).with_context(|| format!("reading {}", material_json.display()))?,
+            )
+            .with_context(|| format!("parsing {}", material_json.display()))?;
 
             let target_path = basisu_cache_path.join(material_name);
-            create_dir_all(&target_path)?;
+            create_dir_all(&target_path).with_context(|| format!("creating {}", target_path.display()))?;
+            let temp_file_path = target_path.join("mtldbasisu.tmp");
 
             let target_material_json = target_path.join("Material.json");
-            if force_basisu || !target_material_json.exists() {
-                copy(&material_json, target_material_json)?;
+            let material_input_hash = manifest.hasher("basisu", OUTPUT_VERSION).file(&material_json)?.finish();
+            let material_json_outdated =
+                force_basisu || !manifest.is_up_to_date(&target_material_json, &material_input_hash);
+            if material_json_outdated {
+                copy(&material_json, &target_material_json)
+                    .with_context(|| format!("writing {}", target_material_json.display()))?;
+                manifest.record(&target_material_json, &material_input_hash);
             }
+            outputs.push(target_material_json, material_json_outdated);
 
             let albedo_source_path = material_path.join(format!("{}_A.png", material_name));
             if albedo_source_path.exists() {
                 let albedo_target_path = target_path.join(format!("{}_A.basisu", material_name));
-                if force_basisu || !albedo_target_path.exists() {
-                    let has_opacity = material.get("opacity").and_then(|f| f.as_bool()).unwrap_or_default();
-                    let albedo_image = load(BufReader::new(File::open(&albedo_source_path)?), ImageFormat::Png)?;
-                    if has_opacity {
+                let input_hash = image_input_hash(manifest, &albedo_source_path, &material_json, compress_settings)?;
+                let outdated = force_basisu || !manifest.is_up_to_date(&albedo_target_path, &input_hash);
+                if outdated {
+                    let has_alpha = material.get("alpha").and_then(|f| f.as_bool()).unwrap_or_default();
+                    let albedo_image = pack::load_png(&albedo_source_path)?;
+                    if has_alpha {
                         if albedo_image.color() != ColorType::Rgba8 {
                             return Err(ApplicationError::InvalidImage(albedo_source_path));
                         }
@@ -81,14 +206,15 @@ fn compress_single_material(
                         return Err(ApplicationError::InvalidImage(albedo_source_path));
                     }
 
-                    let mut compressor_params = common_compressor_params();
+                    let mut compressor_params = common_compressor_params(compress_settings);
                     compressor_params.set_color_space(ColorSpace::Srgb);
                     compressor_params.source_image_mut(0).init(
                         albedo_image.as_bytes(),
                         albedo_image.width(),
                         albedo_image.height(),
-                        if has_opacity { 4 } else { 3 },
+                        if has_alpha { 4 } else { 3 },
                     );
+                    set_source_mips(&mut compressor_params, &albedo_image, MipSettings::albedo(&material));
 
                     let mut compressor = Compressor::new(1);
                     unsafe {
@@ -96,21 +222,27 @@ fn compress_single_material(
                         compressor.process()?;
                     }
 
-                    write(temp_file_path, compressor.basis_file())?;
-                    rename(temp_file_path, albedo_target_path)?;
+                    write(&temp_file_path, compressor.basis_file())
+                        .with_context(|| format!("writing {}", temp_file_path.display()))?;
+                    rename(&temp_file_path, &albedo_target_path)
+                        .with_context(|| format!("writing {}", albedo_target_path.display()))?;
+                    manifest.record(&albedo_target_path, &input_hash);
                 }
+                outputs.push(albedo_target_path, outdated);
             }
 
             let normal_source_path = material_path.join(format!("{}_N.png", material_name));
             if normal_source_path.exists() {
                 let normal_target_path = target_path.join(format!("{}_N.basisu", material_name));
-                if force_basisu || !normal_target_path.exists() {
-                    let normal_image = load(BufReader::new(File::open(&normal_source_path)?), ImageFormat::Png)?;
+                let input_hash = image_input_hash(manifest, &normal_source_path, &material_json, compress_settings)?;
+                let outdated = force_basisu || !manifest.is_up_to_date(&normal_target_path, &input_hash);
+                if outdated {
+                    let normal_image = pack::load_png(&normal_source_path)?;
                     if normal_image.color() != ColorType::Rgb8 {
                         return Err(ApplicationError::InvalidImage(normal_source_path));
                     }
 
-                    let mut compressor_params = common_compressor_params();
+                    let mut compressor_params = common_compressor_params(compress_settings);
                     compressor_params.set_color_space(ColorSpace::Linear);
                     compressor_params.tune_for_normal_maps();
                     compressor_params.source_image_mut(0).init(
@@ -119,6 +251,7 @@ fn compress_single_material(
                         normal_image.height(),
                         3,
                     );
+                    set_source_mips(&mut compressor_params, &normal_image, MipSettings::normal());
 
                     let mut compressor = Compressor::new(1);
                     unsafe {
@@ -126,24 +259,28 @@ fn compress_single_material(
                         compressor.process()?;
                     }
 
-                    write(temp_file_path, compressor.basis_file())?;
-                    rename(temp_file_path, &normal_target_path)?;
+                    write(&temp_file_path, compressor.basis_file())
+                        .with_context(|| format!("writing {}", temp_file_path.display()))?;
+                    rename(&temp_file_path, &normal_target_path)
+                        .with_context(|| format!("writing {}", normal_target_path.display()))?;
+                    manifest.record(&normal_target_path, &input_hash);
                 }
+                outputs.push(normal_target_path, outdated);
             }
 
             let material_pack_source_path = material_path.join(format!("{}_MODR.png", material_name));
             if material_pack_source_path.exists() {
                 let material_pack_target_path = target_path.join(format!("{}_MODR.basisu", material_name));
-                if force_basisu || !material_pack_target_path.exists() {
-                    let material_pack_image = load(
-                        BufReader::new(File::open(&material_pack_source_path)?),
-                        ImageFormat::Png,
-                    )?;
+                let input_hash =
+                    image_input_hash(manifest, &material_pack_source_path, &material_json, compress_settings)?;
+                let outdated = force_basisu || !manifest.is_up_to_date(&material_pack_target_path, &input_hash);
+                if outdated {
+                    let material_pack_image = pack::load_png(&material_pack_source_path)?;
                     if material_pack_image.color() != ColorType::Rgba8 {
                         return Err(ApplicationError::InvalidImage(material_pack_source_path));
                     }
 
-                    let mut compressor_params = common_compressor_params();
+                    let mut compressor_params = common_compressor_params(compress_settings);
                     compressor_params.set_color_space(ColorSpace::Linear);
                     compressor_params.source_image_mut(0).init(
                         material_pack_image.as_bytes(),
@@ -151,6 +288,7 @@ fn compress_single_material(
                         material_pack_image.height(),
                         4,
                     );
+                    set_source_mips(&mut compressor_params, &material_pack_image, MipSettings::linear());
 
                     let mut compressor = Compressor::new(1);
                     unsafe {
@@ -158,20 +296,35 @@ fn compress_single_material(
                         compressor.process()?;
                     }
 
-                    write(temp_file_path, compressor.basis_file())?;
-                    rename(temp_file_path, &material_pack_target_path)?;
+                    write(&temp_file_path, compressor.basis_file())
+                        .with_context(|| format!("writing {}", temp_file_path.display()))?;
+                    rename(&temp_file_path, &material_pack_target_path)
+                        .with_context(|| format!("writing {}", material_pack_target_path.display()))?;
+                    manifest.record(&material_pack_target_path, &input_hash);
                 }
+                outputs.push(material_pack_target_path, outdated);
             }
         }
     }
-    Ok(())
+    Ok(outputs)
 }
 
-fn common_compressor_params() -> CompressorParams {
+fn common_compressor_params(compress_settings: &CompressSettings) -> CompressorParams {
     let mut compressor_params = CompressorParams::new();
     compressor_params.set_basis_format(BasisTextureFormat::UASTC4x4);
-    compressor_params.set_uastc_quality_level(UastcPackFlags_PackUASTCLevelSlower as _);
-    compressor_params.set_rdo_uastc(Some(0.5));
-    compressor_params.set_generate_mipmaps(true);
+    compressor_params.set_uastc_quality_level(compress_settings.uastc_level);
+    compressor_params.set_rdo_uastc((compress_settings.rdo_quality > 0.0).then_some(compress_settings.rdo_quality));
     compressor_params
 }
+
+/// Adds the mip chain of `mips::generate_mips` to the source image, Basis Universal doesn't preserve alpha coverage in
+/// the mips it generates.
+fn set_source_mips(compressor_params: &mut CompressorParams, image: &DynamicImage, mip_settings: MipSettings) {
+    let mips = mips::generate_mips(&image.to_rgba8(), mip_settings);
+    for (level_index, level) in mips.iter().enumerate().skip(1) {
+        // Level 0 is the source image itself.
+        compressor_params
+            .source_mipmap_image_mut(0, level_index as u32 - 1)
+            .init(level.as_raw(), level.width(), level.height(), 4);
+    }
+}
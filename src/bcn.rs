@@ -0,0 +1,447 @@
+// Copyright (c) 2021 Kyrylo Bazhenov
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use image::*;
+use rayon::iter::*;
+
+/// Interpolation weights of BC7 4 bit indices, out of 64.
+const BC7_WEIGHTS: [u32; 16] = [0, 4, 9, 13, 17, 21, 26, 30, 34, 38, 43, 47, 51, 55, 60, 64];
+
+/// Rounds of least squares endpoint refitting after the first fit along the principal axis.
+const BC7_REFINE_STEPS: usize = 2;
+
+/// Block compressed formats written by the DDS stage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BcFormat {
+    /// One channel, the red channel of the image.
+    Bc4,
+    /// Two channels, the red and green channels of the image.
+    Bc5,
+    /// Four channels.
+    Bc7,
+}
+
+impl BcFormat {
+    pub fn block_size(self) -> usize {
+        match self {
+            Self::Bc4 => 8,
+            Self::Bc5 | Self::Bc7 => 16,
+        }
+    }
+}
+
+/// Compresses an image to 4x4 blocks, row by row. Blocks at the right and bottom edges of images with sizes that aren't
+/// a multiple of 4 repeat the last texel.
+pub fn compress(image: &RgbaImage, format: BcFormat) -> Vec<u8> {
+    let (width, height) = image.dimensions();
+    let (blocks_x, blocks_y) = (width.div_ceil(4), height.div_ceil(4));
+
+    (0..blocks_x * blocks_y)
+        .into_par_iter()
+        .flat_map_iter(|block_index| {
+            let (block_x, block_y) = (block_index % blocks_x, block_index / blocks_x);
+            let texels: [[u8; 4]; 16] = std::array::from_fn(|texel_index| {
+                let x = (block_x * 4 + texel_index as u32 % 4).min(width - 1);
+                let y = (block_y * 4 + texel_index as u32 / 4).min(height - 1);
+                image.get_pixel(x, y).0
+            });
+
+            let mut block = Vec::with_capacity(format.block_size());
+            match format {
+                BcFormat::Bc4 => block.extend_from_slice(&encode_bc4_block(texels.map(|f| f[0]))),
+                BcFormat::Bc5 => {
+                    block.extend_from_slice(&encode_bc4_block(texels.map(|f| f[0])));
+                    block.extend_from_slice(&encode_bc4_block(texels.map(|f| f[1])));
+                }
+                BcFormat::Bc7 => block.extend_from_slice(&encode_bc7_block(&texels)),
+            }
+            block
+        })
+        .collect()
+}
+
+/// Encodes a single channel block with the 8 value palette: both endpoints and 6 values between them.
+fn encode_bc4_block(values: [u8; 16]) -> [u8; 8] {
+    let (low, high) = (
+        values.iter().copied().min().unwrap_or_default(),
+        values.iter().copied().max().unwrap_or_default(),
+    );
+
+    let mut block = [0; 8];
+    block[0] = high;
+    block[1] = low;
+    if high == low {
+        return block;
+    }
+
+    // Palette order is high, low, then 6 values from high to low.
+    let palette: [u32; 8] = std::array::from_fn(|index| match index {
+        0 => high as u32,
+        1 => low as u32,
+        _ => ((8 - index as u32) * high as u32 + (index as u32 - 1) * low as u32 + 3) / 7,
+    });
+
+    let mut indices = 0u64;
+    for (texel_index, value) in values.iter().enumerate() {
+        let palette_index = (0..8)
+            .min_by_key(|index| palette[*index].abs_diff(*value as u32))
+            .unwrap_or_default();
+        indices |= (palette_index as u64) << (texel_index * 3);
+    }
+    block[2..].copy_from_slice(&indices.to_le_bytes()[..6]);
+    block
+}
+
+/// Encodes a block with BC7 mode 6: a single subset with RGBA endpoints of 7 bits and a shared lowest bit each, and 4 bit
+/// indices. Endpoints are fitted along the principal axis of the texel colors, then refitted with least squares.
+fn encode_bc7_block(texels: &[[u8; 4]; 16]) -> [u8; 16] {
+    let texels = texels.map(|f| f.map(|channel| channel as f32));
+
+    let mean: [f32; 4] = std::array::from_fn(|channel| texels.iter().map(|f| f[channel]).sum::<f32>() / 16.0);
+
+    let axis = principal_axis(&texels, &mean);
+    let projections = texels.map(|f| {
+        (0..4)
+            .map(|channel| (f[channel] - mean[channel]) * axis[channel])
+            .sum::<f32>()
+    });
+    let (min_projection, max_projection) = projections
+        .iter()
+        .fold((f32::MAX, f32::MIN), |(min, max), f| (min.min(*f), max.max(*f)));
+    let endpoints = [min_projection, max_projection]
+        .map(|projection| std::array::from_fn(|channel| mean[channel] + axis[channel] * projection));
+
+    let mut best = encode_bc7_endpoints(&texels, &endpoints);
+    for _ in 0..BC7_REFINE_STEPS {
+        let Some(endpoints) = refit_endpoints(&texels, &best.indices) else {
+            break;
+        };
+        let candidate = encode_bc7_endpoints(&texels, &endpoints);
+        if candidate.error >= best.error {
+            break;
+        }
+        best = candidate;
+    }
+    best.pack()
+}
+
+/// Direction of the largest variance of the block colors, by power iteration on their covariance.
+fn principal_axis(texels: &[[f32; 4]; 16], mean: &[f32; 4]) -> [f32; 4] {
+    let mut covariance = [[0.0f32; 4]; 4];
+    for texel in texels {
+        let delta: [f32; 4] = std::array::from_fn(|channel| texel[channel] - mean[channel]);
+        for (row, covariance_row) in covariance.iter_mut().enumerate() {
+            for (column, value) in covariance_row.iter_mut().enumerate() {
+                *value += delta[row] * delta[column];
+            }
+        }
+    }
+
+    // Starting from the row of the channel that varies most can't be orthogonal to the principal axis.
+    let start_row = (0..4)
+        .max_by(|a, b| covariance[*a][*a].total_cmp(&covariance[*b][*b]))
+        .unwrap_or_default();
+    let mut axis = covariance[start_row];
+    if axis.iter().all(|f| f.abs() < 1e-6) {
+        return [0.0; 4];
+    }
+    for _ in 0..8 {
+        let next: [f32; 4] =
+            std::array::from_fn(|row| (0..4).map(|column| covariance[row][column] * axis[column]).sum());
+        let length = next.iter().map(|f| f * f).sum::<f32>().sqrt();
+        if length < 1e-6 {
+            break;
+        }
+        axis = next.map(|f| f / length);
+    }
+    axis
+}
+
+/// Least squares endpoints for the chosen indices, none when every texel uses the same weight.
+fn refit_endpoints(texels: &[[f32; 4]; 16], indices: &[u8; 16]) -> Option<[[f32; 4]; 2]> {
+    let (mut aa, mut ab, mut bb) = (0.0f32, 0.0f32, 0.0f32);
+    let (mut ax, mut bx) = ([0.0f32; 4], [0.0f32; 4]);
+    for (texel, index) in texels.iter().zip(indices) {
+        let b = BC7_WEIGHTS[*index as usize] as f32 / 64.0;
+        let a = 1.0 - b;
+        aa += a * a;
+        ab += a * b;
+        bb += b * b;
+        for channel in 0..4 {
+            ax[channel] += a * texel[channel];
+            bx[channel] += b * texel[channel];
+        }
+    }
+
+    let determinant = aa * bb - ab * ab;
+    if determinant.abs() < 1e-6 {
+        return None;
+    }
+    Some([
+        std::array::from_fn(|channel| (bb * ax[channel] - ab * bx[channel]) / determinant),
+        std::array::from_fn(|channel| (aa * bx[channel] - ab * ax[channel]) / determinant),
+    ])
+}
+
+struct Bc7Block {
+    /// 7 bit endpoint channels.
+    endpoints: [[u8; 4]; 2],
+    p_bits: [u8; 2],
+    indices: [u8; 16],
+    error: f32,
+}
+
+/// Quantizes endpoints to 7 bits with the lowest bit that fits them best, then picks the closest palette entry per texel.
+fn encode_bc7_endpoints(texels: &[[f32; 4]; 16], endpoints: &[[f32; 4]; 2]) -> Bc7Block {
+    let mut quantized = [[0u8; 4]; 2];
+    let mut p_bits = [0u8; 2];
+    for (endpoint_index, endpoint) in endpoints.iter().enumerate() {
+        let candidates = [0u8, 1].map(|p_bit| {
+            let channels =
+                endpoint.map(|f| ((f.clamp(0.0, 255.0) - p_bit as f32) / 2.0).round().clamp(0.0, 127.0) as u8);
+            let error = (0..4)
+                .map(|channel| {
+                    let value = ((channels[channel] as u32) << 1 | p_bit as u32) as f32;
+                    (value - endpoint[channel]).powi(2)
+                })
+                .sum::<f32>();
+            (channels, p_bit, error)
+        });
+        let (channels, p_bit, _) = if candidates[0].2 <= candidates[1].2 {
+            candidates[0]
+        } else {
+            candidates[1]
+        };
+        quantized[endpoint_index] = channels;
+        p_bits[endpoint_index] = p_bit;
+    }
+
+    let unquantized: [[u32; 4]; 2] = std::array::from_fn(|endpoint_index| {
+        quantized[endpoint_index].map(|f| (f as u32) << 1 | p_bits[endpoint_index] as u32)
+    });
+    let palette: [[f32; 4]; 16] = std::array::from_fn(|index| {
+        let weight = BC7_WEIGHTS[index];
+        std::array::from_fn(|channel| {
+            (((64 - weight) * unquantized[0][channel] + weight * unquantized[1][channel] + 32) >> 6) as f32
+        })
+    });
+
+    let mut indices = [0u8; 16];
+    let mut error = 0.0;
+    for (texel, texel_index) in texels.iter().zip(indices.iter_mut()) {
+        let (palette_index, palette_error) = palette
+            .iter()
+            .map(|entry| {
+                (0..4)
+                    .map(|channel| (entry[channel] - texel[channel]).powi(2))
+                    .sum::<f32>()
+            })
+            .enumerate()
+            .fold(
+                (0, f32::MAX),
+                |best, (index, error)| if error < best.1 { (index, error) } else { best },
+            );
+        *texel_index = palette_index as u8;
+        error += palette_error;
+    }
+
+    Bc7Block {
+        endpoints: quantized,
+        p_bits,
+        indices,
+        error,
+    }
+}
+
+impl Bc7Block {
+    fn pack(mut self) -> [u8; 16] {
+        // The highest bit of the first index is implied 0, swapping the endpoints makes it so.
+        if self.indices[0] >= 8 {
+            self.endpoints.swap(0, 1);
+            self.p_bits.swap(0, 1);
+            self.indices = self.indices.map(|f| 15 - f);
+        }
+
+        let mut bits = 1u128 << 6;
+        let mut offset = 7;
+        let mut push = |value: u128, length: u32| {
+            bits |= value << offset;
+            offset += length;
+        };
+        for channel in 0..4 {
+            push(self.endpoints[0][channel] as u128, 7);
+            push(self.endpoints[1][channel] as u128, 7);
+        }
+        push(self.p_bits[0] as u128, 1);
+        push(self.p_bits[1] as u128, 1);
+        for (texel_index, index) in self.indices.iter().enumerate() {
+            push(*index as u128, if texel_index == 0 { 3 } else { 4 });
+        }
+        bits.to_le_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_bc4_block(block: &[u8]) -> [u8; 16] {
+        let (first, second) = (block[0] as f32, block[1] as f32);
+        let palette: [f32; 8] = std::array::from_fn(|index| match index {
+            0 => first,
+            1 => second,
+            _ if first > second => ((8 - index) as f32 * first + (index - 1) as f32 * second) / 7.0,
+            6 => 0.0,
+            7 => 255.0,
+            _ => ((6 - index) as f32 * first + (index - 1) as f32 * second) / 5.0,
+        });
+        let mut bytes = [0; 8];
+        bytes[..6].copy_from_slice(&block[2..8]);
+        let indices = u64::from_le_bytes(bytes);
+        std::array::from_fn(|texel_index| palette[(indices >> (texel_index * 3) & 7) as usize].round() as u8)
+    }
+
+    /// Decodes BC7 mode 6 only, the one mode the encoder writes.
+    fn decode_bc7_block(block: &[u8]) -> [[u8; 4]; 16] {
+        let bits = u128::from_le_bytes(block.try_into().unwrap());
+        assert_eq!(bits & 0x7f, 1 << 6, "mode 6");
+
+        let mut offset = 7;
+        let mut read = |length: u32| {
+            let value = (bits >> offset) & ((1 << length) - 1);
+            offset += length;
+            value as u32
+        };
+        let mut endpoints = [[0u32; 4]; 2];
+        for channel in 0..4 {
+            for endpoint in &mut endpoints {
+                endpoint[channel] = read(7);
+            }
+        }
+        let p_bits = [read(1), read(1)];
+        let endpoints = [0, 1].map(|index| endpoints[index].map(|f| f << 1 | p_bits[index]));
+
+        std::array::from_fn(|texel_index| {
+            let weight = BC7_WEIGHTS[read(if texel_index == 0 { 3 } else { 4 }) as usize];
+            std::array::from_fn(|channel| {
+                (((64 - weight) * endpoints[0][channel] + weight * endpoints[1][channel] + 32) >> 6) as u8
+            })
+        })
+    }
+
+    fn max_error(decoded: &[[u8; 4]; 16], texels: &[[u8; 4]; 16]) -> u8 {
+        decoded
+            .iter()
+            .flatten()
+            .zip(texels.iter().flatten())
+            .map(|(a, b)| a.abs_diff(*b))
+            .max()
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn bc4_flat_block_is_exact() {
+        let block = encode_bc4_block([93; 16]);
+        assert_eq!(decode_bc4_block(&block), [93; 16]);
+    }
+
+    #[test]
+    fn bc4_gradient_is_within_palette_step() {
+        let values: [u8; 16] = std::array::from_fn(|index| 20 + index as u8 * 13);
+        let block = encode_bc4_block(values);
+        assert!(block[0] > block[1], "8 value palette");
+
+        let decoded = decode_bc4_block(&block);
+        for (decoded, value) in decoded.iter().zip(values) {
+            assert!(decoded.abs_diff(value) <= (195 / 14) + 1, "{} != {}", decoded, value);
+        }
+    }
+
+    #[test]
+    fn bc4_two_values_are_exact() {
+        let values: [u8; 16] = std::array::from_fn(|index| if index % 3 == 0 { 12 } else { 240 });
+        let block = encode_bc4_block(values);
+        assert_eq!(decode_bc4_block(&block), values);
+    }
+
+    #[test]
+    fn bc5_stores_red_then_green() {
+        let image = RgbaImage::from_fn(4, 4, |x, y| Rgba([(x * 60) as u8, (y * 70) as u8, 255, 255]));
+        let compressed = compress(&image, BcFormat::Bc5);
+        assert_eq!(compressed.len(), 16);
+
+        let (red, green) = (decode_bc4_block(&compressed[..8]), decode_bc4_block(&compressed[8..]));
+        for (texel_index, pixel) in image.pixels().enumerate() {
+            assert!(red[texel_index].abs_diff(pixel[0]) <= 10);
+            assert!(green[texel_index].abs_diff(pixel[1]) <= 10);
+        }
+    }
+
+    #[test]
+    fn bc7_flat_block_is_within_p_bit() {
+        let texels = [[37, 128, 201, 255]; 16];
+        let decoded = decode_bc7_block(&encode_bc7_block(&texels));
+        assert!(max_error(&decoded, &texels) <= 1);
+    }
+
+    #[test]
+    fn bc7_two_color_block_is_within_quantization() {
+        let texels: [[u8; 4]; 16] = std::array::from_fn(|index| {
+            if index % 5 < 2 {
+                [10, 200, 30, 255]
+            } else {
+                [240, 20, 100, 0]
+            }
+        });
+        let decoded = decode_bc7_block(&encode_bc7_block(&texels));
+        assert!(max_error(&decoded, &texels) <= 2);
+    }
+
+    #[test]
+    fn bc7_gradient_is_within_tolerance() {
+        let texels: [[u8; 4]; 16] = std::array::from_fn(|index| {
+            let value = index as u8 * 16;
+            [value, 255 - value, value / 2, 255]
+        });
+        let decoded = decode_bc7_block(&encode_bc7_block(&texels));
+        assert!(max_error(&decoded, &texels) <= 8);
+    }
+
+    #[test]
+    fn bc7_pack_swaps_endpoints_when_first_index_has_high_bit() {
+        let block = Bc7Block {
+            endpoints: [[0, 10, 20, 127], [127, 100, 60, 0]],
+            p_bits: [0, 1],
+            indices: std::array::from_fn(|index| 15 - index as u8),
+            error: 0.0,
+        };
+        let expected: [[u8; 4]; 16] = std::array::from_fn(|texel_index| {
+            let weight = BC7_WEIGHTS[block.indices[texel_index] as usize];
+            std::array::from_fn(|channel| {
+                let [first, second] =
+                    [0, 1].map(|f| (block.endpoints[f][channel] as u32) << 1 | block.p_bits[f] as u32);
+                (((64 - weight) * first + weight * second + 32) >> 6) as u8
+            })
+        });
+
+        // The first index is stored with 3 bits, without the swap it would decode as 7 instead of 15.
+        assert_eq!(decode_bc7_block(&block.pack()), expected);
+    }
+
+    #[test]
+    fn edge_blocks_cover_sizes_that_are_not_multiples_of_4() {
+        let image = RgbaImage::from_fn(6, 5, |x, y| Rgba([(x * 40) as u8, (y * 50) as u8, 0, 255]));
+        assert_eq!(compress(&image, BcFormat::Bc4).len(), 2 * 2 * 8);
+        assert_eq!(compress(&image, BcFormat::Bc5).len(), 2 * 2 * 16);
+
+        let compressed = compress(&image, BcFormat::Bc7);
+        assert_eq!(compressed.len(), 2 * 2 * 16);
+
+        // The last block repeats the right column and the bottom row.
+        let decoded = decode_bc7_block(&compressed[48..]);
+        let expected = std::array::from_fn(|texel_index| image.get_pixel((4 + texel_index as u32 % 4).min(5), 4).0);
+        assert!(max_error(&decoded, &expected) <= 2);
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright (c) 2021 Kyrylo Bazhenov
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::BTreeMap;
+use std::fs::*;
+use std::path::*;
+use uuid::*;
+
+use crate::application_error::*;
+use crate::template;
+
+pub fn generate_blender(
+    pack_cache_path: &Path,
+    blender_cache_path: &Path,
+    blender_script_template: &Path,
+) -> Result<(), ApplicationError> {
+    create_dir_all(blender_cache_path).with_context(|| format!("creating {}", blender_cache_path.display()))?;
+
+    let mut templates = template::create_registry();
+    let script_template = template::register_template(&mut templates, blender_script_template)?;
+
+    let mut material_categories = BTreeMap::new();
+    for dir in read_dir(pack_cache_path).with_context(|| format!("reading {}", pack_cache_path.display()))? {
+        let material_path = dir?.path();
+
+        let material_json = material_path.join("Material.json");
+        if material_json.exists() {
+            let material: serde_json::Value = serde_json::from_str(
+                &read_to_string(&material_json).with_context(|| format!("reading {}", material_json.display()))?,
+            )
+            .with_context(|| format!("parsing {}", material_json.display()))?;
+            // Uncategorized materials have a null category and stay out of the catalogs.
+            if let Some(material_category) = material.get("category").and_then(|f| f.as_str()) {
+                material_categories
+                    .entry(material_category.to_owned())
+                    .or_insert_with(|| catalog_uuid(material_category));
+            }
+        }
+    }
+
+    log::info!("BLENDER {} categories", material_categories.len());
+
+    let catalog_file_path = blender_cache_path.join("blender_assets.cats.txt");
+    let temp_file_path = blender_cache_path.join("mtldblender.tmp");
+
+    let mut catalog = String::from(
+        "# This is an Asset Catalog Definition file for Blender.\n# Generated by mtld, do not edit.\n\nVERSION 1\n\n",
+    );
+    for (material_category, category_uuid) in &material_categories {
+        catalog.push_str(&format!(
+            "{}:Materials/{}:{}\n",
+            category_uuid, material_category, material_category
+        ));
+    }
+    write(&temp_file_path, catalog).with_context(|| format!("writing {}", temp_file_path.display()))?;
+    rename(&temp_file_path, &catalog_file_path).with_context(|| format!("writing {}", catalog_file_path.display()))?;
+
+    let catalogs = material_categories
+        .iter()
+        .map(|(material_category, category_uuid)| {
+            serde_json::json!({ "category": material_category, "uuid": category_uuid.to_string() })
+        })
+        .collect::<Vec<_>>();
+
+    write(
+        &temp_file_path,
+        templates.render(
+            &script_template,
+            &serde_json::json!({
+                "pack_cache_path": absolute_path(pack_cache_path)?,
+                "catalog_file_path": absolute_path(&catalog_file_path)?,
+                "catalogs": catalogs,
+            }),
+        )?,
+    )
+    .with_context(|| format!("writing {}", temp_file_path.display()))?;
+    rename(&temp_file_path, blender_cache_path.join("mtld_import.py"))
+        .with_context(|| format!("writing {}", blender_cache_path.join("mtld_import.py").display()))?;
+
+    Ok(())
+}
+
+/// Catalog UUIDs are derived from the category name, so re-importing keeps assets in the same catalogs.
+fn catalog_uuid(material_category: &str) -> Uuid {
+    Uuid::new_v5(
+        &Uuid::NAMESPACE_URL,
+        format!("mtld:blender-catalog:Materials/{}", material_category).as_bytes(),
+    )
+}
+
+fn absolute_path(path: &Path) -> Result<String, ApplicationError> {
+    let path = std::path::absolute(path)?;
+    match path.to_str() {
+        Some(path) => Ok(path.to_owned()),
+        None => Err(ApplicationError::InvalidParameter(path.to_string_lossy().into_owned())),
+    }
+}
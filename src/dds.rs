@@ -0,0 +1,413 @@
+// Copyright (c) 2021 Kyrylo Bazhenov
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use image::*;
+use rayon::iter::*;
+use std::collections::BTreeSet;
+use std::fs::*;
+use std::path::*;
+
+use crate::application_error::*;
+use crate::bcn::{self, BcFormat};
+use crate::failures::*;
+use crate::logging::*;
+use crate::manifest::*;
+use crate::material::*;
+use crate::mips::{self, MipSettings};
+use crate::pack;
+
+/// Output version of DDS textures, bump when the encoders or headers change what gets written.
+const OUTPUT_VERSION: u32 = 2;
+
+const DDS_MAGIC: &[u8; 4] = b"DDS ";
+const DDSD_CAPS: u32 = 0x1;
+const DDSD_HEIGHT: u32 = 0x2;
+const DDSD_WIDTH: u32 = 0x4;
+const DDSD_PIXELFORMAT: u32 = 0x1000;
+const DDSD_MIPMAPCOUNT: u32 = 0x20000;
+const DDSD_LINEARSIZE: u32 = 0x80000;
+const DDPF_FOURCC: u32 = 0x4;
+/// Marks tangent-space normal maps, as NVIDIA Texture Tools do. Normals stored in two channels need Z reconstructed.
+const DDPF_NORMAL: u32 = 0x80000000;
+const DDSCAPS_COMPLEX: u32 = 0x8;
+const DDSCAPS_TEXTURE: u32 = 0x1000;
+const DDSCAPS_MIPMAP: u32 = 0x400000;
+const DDS_DIMENSION_TEXTURE2D: u32 = 3;
+
+const DXGI_FORMAT_BC4_UNORM: u32 = 80;
+const DXGI_FORMAT_BC5_UNORM: u32 = 83;
+const DXGI_FORMAT_BC7_UNORM: u32 = 98;
+const DXGI_FORMAT_BC7_UNORM_SRGB: u32 = 99;
+
+/// Role of a DDS file, which decides the packed image it is built from, its format and how its mips are filtered.
+#[derive(Debug, Clone, Copy)]
+enum DdsTexture {
+    /// `_A.png` as BC7, alpha is kept for cutout and decal materials.
+    Albedo,
+    /// X and Y of `_N.png` as BC5, Z is reconstructed when sampling.
+    Normal,
+    /// `_MODR.png` as BC7.
+    MaterialPack,
+    /// Displacement from the blue channel of `_MODR.png` as BC4, with the full precision of a single channel.
+    Height,
+    /// `_E.png` as BC7.
+    Emission,
+    /// `_STS.png` as BC7.
+    Surface,
+}
+
+impl DdsTexture {
+    const ALL: [Self; 6] = [
+        Self::Albedo,
+        Self::Normal,
+        Self::MaterialPack,
+        Self::Height,
+        Self::Emission,
+        Self::Surface,
+    ];
+
+    fn source_suffix(self) -> &'static str {
+        match self {
+            Self::Albedo => "A",
+            Self::Normal => "N",
+            Self::MaterialPack | Self::Height => "MODR",
+            Self::Emission => "E",
+            Self::Surface => "STS",
+        }
+    }
+
+    fn suffix(self) -> &'static str {
+        match self {
+            Self::Height => "H",
+            _ => self.source_suffix(),
+        }
+    }
+
+    fn format(self) -> BcFormat {
+        match self {
+            Self::Normal => BcFormat::Bc5,
+            Self::Height => BcFormat::Bc4,
+            Self::Albedo | Self::MaterialPack | Self::Emission | Self::Surface => BcFormat::Bc7,
+        }
+    }
+
+    fn srgb(self) -> bool {
+        matches!(self, Self::Albedo | Self::Emission)
+    }
+
+    fn mip_settings(self, material: &serde_json::Value) -> MipSettings {
+        match self {
+            Self::Albedo => MipSettings::albedo(material),
+            Self::Normal => MipSettings::normal(),
+            Self::Emission => MipSettings {
+                srgb: true,
+                ..MipSettings::linear()
+            },
+            Self::MaterialPack | Self::Height | Self::Surface => MipSettings::linear(),
+        }
+    }
+
+    /// Only materials with displacement get a height map, other packed images are compressed whenever they exist.
+    fn has_data(self, material: &serde_json::Value) -> bool {
+        match self {
+            Self::Height => material
+                .get(pack::MATERIAL_PACK_LAYOUT[2].map)
+                .and_then(|f| f.as_bool())
+                .unwrap_or_default(),
+            _ => true,
+        }
+    }
+
+    /// Moves the channels the format stores to the front.
+    fn swizzle(self, image: RgbaImage) -> RgbaImage {
+        match self {
+            Self::Height => RgbaImage::from_fn(image.width(), image.height(), |x, y| {
+                let displacement = image.get_pixel(x, y)[2];
+                Rgba([displacement, displacement, displacement, 255])
+            }),
+            _ => image,
+        }
+    }
+}
+
+pub fn compress_dds(
+    pack_cache_path: &Path,
+    force_dds: bool,
+    dds_cache_path: &Path,
+    only_materials: Option<&BTreeSet<String>>,
+    failures: &FailureLog,
+) -> Result<(), ApplicationError> {
+    create_dir_all(dds_cache_path).with_context(|| format!("creating {}", dds_cache_path.display()))?;
+
+    let material_paths = material_paths(pack_cache_path, only_materials)?;
+    let manifest = BuildManifest::load(dds_cache_path)?;
+    let result = compress_all(&manifest, failures, &material_paths, force_dds, dds_cache_path);
+    manifest.save()?;
+    result
+}
+
+fn compress_all(
+    manifest: &BuildManifest,
+    failures: &FailureLog,
+    material_paths: &[PathBuf],
+    force_dds: bool,
+    dds_cache_path: &Path,
+) -> Result<(), ApplicationError> {
+    log::info!("DDS {} materials", material_paths.len());
+    let progress = StageProgress::new("dds", material_paths.len() as u64);
+
+    material_paths
+        .par_iter()
+        .map(|material_path| {
+            let material_name = material_path.file_name().unwrap_or_default().to_string_lossy();
+            let result = failures.handle(
+                "dds",
+                &material_name,
+                compress_material(manifest, material_path, dds_cache_path, force_dds).map(|_| ()),
+            );
+            progress.inc(&material_name);
+            result
+        })
+        .collect::<Result<Vec<_>, ApplicationError>>()?;
+
+    progress.finish();
+
+    Ok(())
+}
+
+/// Returns true when any DDS file of a packed material was built from other inputs than the current ones.
+pub fn is_outdated(
+    manifest: &BuildManifest,
+    material_path: &Path,
+    dds_cache_path: &Path,
+) -> Result<bool, ApplicationError> {
+    let material_name = material_path.file_name().unwrap_or_default().to_string_lossy();
+    let material_json = material_path.join("Material.json");
+    let target_path = dds_cache_path.join(material_name.as_ref());
+
+    let target_material_json = target_path.join("Material.json");
+    if manifest.is_recorded(&target_material_json)
+        && !manifest.is_up_to_date(
+            &target_material_json,
+            &manifest.hasher("dds", OUTPUT_VERSION).file(&material_json)?.finish(),
+        )
+    {
+        return Ok(true);
+    }
+
+    for dds_texture in DdsTexture::ALL {
+        let source_path = material_path.join(format!("{}_{}.png", material_name, dds_texture.source_suffix()));
+        let target_path = target_path.join(format!("{}_{}.dds", material_name, dds_texture.suffix()));
+        if manifest.is_recorded(&target_path)
+            && !manifest.is_up_to_date(
+                &target_path,
+                &image_input_hash(manifest, &source_path, &material_json, dds_texture)?,
+            )
+        {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+fn image_input_hash(
+    manifest: &BuildManifest,
+    source_path: &Path,
+    material_json: &Path,
+    dds_texture: DdsTexture,
+) -> Result<String, ApplicationError> {
+    Ok(manifest
+        .hasher("dds", OUTPUT_VERSION)
+        .file(source_path)?
+        .file(material_json)?
+        .setting("format", format!("{:?}", dds_texture.format()))
+        .setting("alpha_test_threshold", mips::ALPHA_TEST_THRESHOLD)
+        .finish())
+}
+
+/// Compresses the packed images of one material folder in the pack cache to DDS files with full mip chains in
+/// `<dds_cache_path>/<name>`, skipping DDS files that are up to date.
+pub fn compress_material(
+    manifest: &BuildManifest,
+    material_path: &Path,
+    dds_cache_path: &Path,
+    force_dds: bool,
+) -> Result<MaterialOutputs, ApplicationError> {
+    let mut outputs = MaterialOutputs::new(material_path.file_name().unwrap_or_default().to_string_lossy());
+    if let Some(material_name) = material_path.file_stem().and_then(|p| p.to_str()) {
+        let material_json = material_path.join("Material.json");
+        if material_json.exists() {
+            log::debug!("DDS {:?}", material_name);
+
+            let material: serde_json::Value = serde_json::from_str(
+                &read_to_string(&material_json).with_context(|| format!("reading {}", material_json.display()))?,
+            )
+            .with_context(|| format!("parsing {}", material_json.display()))?;
+
+            let target_path = dds_cache_path.join(material_name);
+            create_dir_all(&target_path).with_context(|| format!("creating {}", target_path.display()))?;
+            let temp_file_path = target_path.join("mtlddds.tmp");
+
+            let target_material_json = target_path.join("Material.json");
+            let material_input_hash = manifest.hasher("dds", OUTPUT_VERSION).file(&material_json)?.finish();
+            let material_json_outdated =
+                force_dds || !manifest.is_up_to_date(&target_material_json, &material_input_hash);
+            if material_json_outdated {
+                copy(&material_json, &target_material_json)
+                    .with_context(|| format!("writing {}", target_material_json.display()))?;
+                manifest.record(&target_material_json, &material_input_hash);
+            }
+            outputs.push(target_material_json, material_json_outdated);
+
+            for dds_texture in DdsTexture::ALL {
+                let source_path = material_path.join(format!("{}_{}.png", material_name, dds_texture.source_suffix()));
+                if !source_path.exists() || !dds_texture.has_data(&material) {
+                    continue;
+                }
+
+                let dds_path = target_path.join(format!("{}_{}.dds", material_name, dds_texture.suffix()));
+                let input_hash = image_input_hash(manifest, &source_path, &material_json, dds_texture)?;
+                let outdated = force_dds || !manifest.is_up_to_date(&dds_path, &input_hash);
+                if outdated {
+                    let image = dds_texture.swizzle(pack::load_png(&source_path)?.to_rgba8());
+                    let mips = mips::generate_mips(&image, dds_texture.mip_settings(&material));
+
+                    write(&temp_file_path, encode_dds(&mips, dds_texture))
+                        .with_context(|| format!("writing {}", temp_file_path.display()))?;
+                    rename(&temp_file_path, &dds_path).with_context(|| format!("writing {}", dds_path.display()))?;
+                    manifest.record(&dds_path, &input_hash);
+                }
+                outputs.push(dds_path, outdated);
+            }
+        }
+    }
+    Ok(outputs)
+}
+
+/// Encodes a mip chain as a DDS file with the DX10 header extension, which every BCn format can be described with.
+fn encode_dds(mips: &[RgbaImage], dds_texture: DdsTexture) -> Vec<u8> {
+    let format = dds_texture.format();
+    let levels = mips
+        .iter()
+        .map(|level| bcn::compress(level, format))
+        .collect::<Vec<_>>();
+
+    let mut dds = Vec::new();
+    dds.extend_from_slice(DDS_MAGIC);
+    for value in [
+        124, // dwSize
+        DDSD_CAPS | DDSD_HEIGHT | DDSD_WIDTH | DDSD_PIXELFORMAT | DDSD_MIPMAPCOUNT | DDSD_LINEARSIZE,
+        mips[0].height(),
+        mips[0].width(),
+        levels[0].len() as u32,
+        0, // dwDepth
+        mips.len() as u32,
+    ] {
+        dds.extend_from_slice(&value.to_le_bytes());
+    }
+    dds.extend_from_slice(&[0; 11 * 4]); // dwReserved1
+
+    let pixel_format_flags = match dds_texture {
+        DdsTexture::Normal => DDPF_FOURCC | DDPF_NORMAL,
+        _ => DDPF_FOURCC,
+    };
+    for value in [32, pixel_format_flags, u32::from_le_bytes(*b"DX10"), 0, 0, 0, 0, 0] {
+        dds.extend_from_slice(&value.to_le_bytes());
+    }
+    for value in [DDSCAPS_COMPLEX | DDSCAPS_TEXTURE | DDSCAPS_MIPMAP, 0, 0, 0, 0] {
+        dds.extend_from_slice(&value.to_le_bytes());
+    }
+
+    let dxgi_format = match (format, dds_texture.srgb()) {
+        (BcFormat::Bc4, _) => DXGI_FORMAT_BC4_UNORM,
+        (BcFormat::Bc5, _) => DXGI_FORMAT_BC5_UNORM,
+        (BcFormat::Bc7, false) => DXGI_FORMAT_BC7_UNORM,
+        (BcFormat::Bc7, true) => DXGI_FORMAT_BC7_UNORM_SRGB,
+    };
+    // Array size 1, no misc flags and unknown alpha mode.
+    for value in [dxgi_format, DDS_DIMENSION_TEXTURE2D, 0, 1, 0] {
+        dds.extend_from_slice(&value.to_le_bytes());
+    }
+
+    for level in levels {
+        dds.extend_from_slice(&level);
+    }
+    dds
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u32(dds: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(dds[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn headers_are_magic_124_bytes_and_dx10() {
+        let dds = encode_dds(&[RgbaImage::new(8, 4)], DdsTexture::Normal);
+        assert_eq!(&dds[..4], DDS_MAGIC);
+        assert_eq!(read_u32(&dds, 4), 124);
+        assert_eq!(read_u32(&dds, 12), 4, "height");
+        assert_eq!(read_u32(&dds, 16), 8, "width");
+        assert_eq!(read_u32(&dds, 20), 2 * 16, "linear size");
+        assert_eq!(read_u32(&dds, 28), 1, "mip count");
+
+        // The pixel format is at 76, 4 bytes of magic and 72 bytes of header before it.
+        assert_eq!(read_u32(&dds, 76), 32);
+        assert_eq!(read_u32(&dds, 80), DDPF_FOURCC | DDPF_NORMAL);
+        assert_eq!(&dds[84..88], b"DX10");
+
+        assert_eq!(read_u32(&dds, 128), DXGI_FORMAT_BC5_UNORM);
+        assert_eq!(read_u32(&dds, 132), DDS_DIMENSION_TEXTURE2D);
+        assert_eq!(read_u32(&dds, 140), 1, "array size");
+        assert_eq!(dds.len(), 148 + 2 * 16);
+    }
+
+    #[test]
+    fn formats_match_textures() {
+        for (dds_texture, dxgi_format) in [
+            (DdsTexture::Albedo, DXGI_FORMAT_BC7_UNORM_SRGB),
+            (DdsTexture::Normal, DXGI_FORMAT_BC5_UNORM),
+            (DdsTexture::MaterialPack, DXGI_FORMAT_BC7_UNORM),
+            (DdsTexture::Height, DXGI_FORMAT_BC4_UNORM),
+            (DdsTexture::Emission, DXGI_FORMAT_BC7_UNORM_SRGB),
+            (DdsTexture::Surface, DXGI_FORMAT_BC7_UNORM),
+        ] {
+            let dds = encode_dds(&[RgbaImage::new(4, 4)], dds_texture);
+            assert_eq!(read_u32(&dds, 128), dxgi_format, "{:?}", dds_texture);
+        }
+    }
+
+    #[test]
+    fn mip_levels_round_up_to_whole_blocks() {
+        for (width, height) in [(6, 5), (10, 3), (1, 1)] {
+            let mips = mips::generate_mips(&RgbaImage::new(width, height), MipSettings::linear());
+            for dds_texture in [DdsTexture::Height, DdsTexture::Normal, DdsTexture::Albedo] {
+                let block_size = dds_texture.format().block_size();
+                let level_sizes = mips
+                    .iter()
+                    .map(|level| {
+                        level.width().div_ceil(4).max(1) as usize
+                            * level.height().div_ceil(4).max(1) as usize
+                            * block_size
+                    })
+                    .collect::<Vec<_>>();
+
+                let dds = encode_dds(&mips, dds_texture);
+                assert_eq!(read_u32(&dds, 28), mips.len() as u32);
+                assert_eq!(read_u32(&dds, 20), level_sizes[0] as u32);
+                assert_eq!(
+                    dds.len(),
+                    148 + level_sizes.iter().sum::<usize>(),
+                    "{}x{}",
+                    width,
+                    height
+                );
+            }
+        }
+    }
+}
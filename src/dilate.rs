@@ -0,0 +1,112 @@
+// Copyright (c) 2021 Kyrylo Bazhenov
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use image::*;
+
+use crate::color::{linear_to_srgb, srgb_to_linear};
+
+/// Texels of one pyramid level: linear color and how much of it comes from visible texels, from 0 to 1.
+struct Level {
+    width: u32,
+    height: u32,
+    texels: Vec<([f32; 3], f32)>,
+}
+
+impl Level {
+    fn get(&self, x: u32, y: u32) -> ([f32; 3], f32) {
+        self.texels[(y.min(self.height - 1) * self.width + x.min(self.width - 1)) as usize]
+    }
+
+    /// Bilinear color of the level at texel centers of the level above it.
+    fn sample_up(&self, x: u32, y: u32) -> [f32; 3] {
+        let u = ((x as f32 + 0.5) * 0.5 - 0.5).max(0.0);
+        let v = ((y as f32 + 0.5) * 0.5 - 0.5).max(0.0);
+        let (x0, y0) = (u as u32, v as u32);
+        let (fu, fv) = (u.fract(), v.fract());
+
+        let mut color = [0.0; 3];
+        for (dx, dy, weight) in [
+            (0, 0, (1.0 - fu) * (1.0 - fv)),
+            (1, 0, fu * (1.0 - fv)),
+            (0, 1, (1.0 - fu) * fv),
+            (1, 1, fu * fv),
+        ] {
+            let (texel, _) = self.get(x0 + dx, y0 + dy);
+            for channel in 0..3 {
+                color[channel] += texel[channel] * weight;
+            }
+        }
+        color
+    }
+}
+
+/// Fills the color of fully transparent texels from the visible texels around them with a push-pull fill, so
+/// filtering, mipmaps and block compression don't bleed unrelated colors into cutout edges. Alpha is kept.
+pub fn dilate_colors(image: &mut RgbaImage) {
+    if image.pixels().all(|f| f[3] > 0) || image.pixels().all(|f| f[3] == 0) {
+        return;
+    }
+
+    let mut levels = vec![Level {
+        width: image.width(),
+        height: image.height(),
+        texels: image
+            .pixels()
+            .map(|f| {
+                let color = [0, 1, 2].map(|channel| srgb_to_linear(f[channel] as f32 / 255.0));
+                (color, if f[3] > 0 { 1.0 } else { 0.0 })
+            })
+            .collect(),
+    }];
+
+    // Push: average the visible colors of every 2x2 block down to a single texel.
+    while let Some(level) = levels.last().filter(|f| f.width > 1 || f.height > 1) {
+        let (width, height) = ((level.width / 2).max(1), (level.height / 2).max(1));
+        let mut texels = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let (mut color, mut weight) = ([0.0; 3], 0.0);
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let (texel_color, texel_weight) = level.get(x * 2 + dx, y * 2 + dy);
+                    for channel in 0..3 {
+                        color[channel] += texel_color[channel] * texel_weight;
+                    }
+                    weight += texel_weight;
+                }
+                if weight > 0.0 {
+                    color = color.map(|f| f / weight);
+                }
+                texels.push((color, weight.min(1.0)));
+            }
+        }
+        levels.push(Level { width, height, texels });
+    }
+
+    // Pull: blend partially covered texels with the filled level below, from the smallest level up.
+    for level_index in (0..levels.len() - 1).rev() {
+        let (finer, coarser) = levels.split_at_mut(level_index + 1);
+        let (level, coarser) = (&mut finer[level_index], &coarser[0]);
+        for y in 0..level.height {
+            for x in 0..level.width {
+                let texel = &mut level.texels[(y * level.width + x) as usize];
+                if texel.1 < 1.0 {
+                    let (color, weight) = texel;
+                    for (color, fill) in color.iter_mut().zip(coarser.sample_up(x, y)) {
+                        *color = *color * *weight + fill * (1.0 - *weight);
+                    }
+                    *weight = 1.0;
+                }
+            }
+        }
+    }
+
+    for (pixel, (color, _)) in image.pixels_mut().zip(&levels[0].texels) {
+        if pixel[3] == 0 {
+            for channel in 0..3 {
+                pixel[channel] = (linear_to_srgb(color[channel]).clamp(0.0, 1.0) * 255.0).round() as u8;
+            }
+        }
+    }
+}
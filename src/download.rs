@@ -8,47 +8,87 @@ use std::fs::*;
 use std::io::{copy, BufWriter, Cursor};
 
 use crate::application_error::*;
+use crate::failures::*;
+use crate::logging::*;
+use crate::manifest::*;
+
+/// Output version of downloaded archives and metadata, bump when either is stored differently.
+const OUTPUT_VERSION: u32 = 1;
+
+/// Assets of the last complete ambientCG listing, stored next to the downloaded archives.
+pub const LISTING_FILE_NAME: &str = ".mtld-listing.json";
+
+pub struct DownloadSettings {
+    pub resolutions: Vec<String>,
+    pub extensions: Vec<String>,
+    pub types_request: String,
+    pub user_agent: String,
+    pub request_limit: u32,
+}
 
 pub fn download_ambientcg(
     force_download: bool,
     download_cache_path: &std::path::Path,
-    download_resolutions: &[String],
-    download_extensions: &[String],
-    download_types_request: &str,
-    user_agent: &str,
-    request_limit: u32,
+    download_settings: &DownloadSettings,
+    failures: &FailureLog,
 ) -> Result<(), ApplicationError> {
-    let mut download_types = Vec::with_capacity(download_resolutions.len() * download_extensions.len());
-    for resolution in download_resolutions {
-        for extension in download_extensions {
+    let mut download_types =
+        Vec::with_capacity(download_settings.resolutions.len() * download_settings.extensions.len());
+    for resolution in &download_settings.resolutions {
+        for extension in &download_settings.extensions {
             download_types.push(format!("{}-{}", resolution, extension));
         }
     }
 
-    create_dir_all(download_cache_path)?;
-    let temp_file_path = download_cache_path.join("mtldownload.tmp");
+    create_dir_all(download_cache_path).with_context(|| format!("creating {}", download_cache_path.display()))?;
+    create_dir_all(download_cache_path.join(".mtld"))
+        .with_context(|| format!("creating {}", download_cache_path.join(".mtld").display()))?;
 
-    let metadata_path = download_cache_path.join(".mtld");
-    create_dir_all(&metadata_path)?;
+    let manifest = BuildManifest::load(download_cache_path)?;
+    let result = download_assets(
+        &manifest,
+        failures,
+        force_download,
+        download_cache_path,
+        &download_types,
+        download_settings,
+    );
+    manifest.save()?;
+    result
+}
+
+fn download_assets(
+    manifest: &BuildManifest,
+    failures: &FailureLog,
+    force_download: bool,
+    download_cache_path: &std::path::Path,
+    download_types: &[String],
+    download_settings: &DownloadSettings,
+) -> Result<(), ApplicationError> {
+    let client = Client::builder().user_agent(&download_settings.user_agent).build()?;
 
-    let client = Client::builder().user_agent(user_agent).build()?;
+    let progress = StageProgress::new("download", 0);
 
+    let mut listed_assets = Vec::new();
+    let mut listing_complete = false;
     let mut request_offset = 0;
     loop {
         let request = client
             .get(format!(
                 "https://ambientcg.com/api/v2/full_json?type={}&offset={}&sort=Latest&include=downloadData&limit={}",
-                download_types_request, request_offset, request_limit
+                download_settings.types_request, request_offset, download_settings.request_limit
             ))
-            .send()?;
+            .send()
+            .with_context(|| "requesting the ambientCG asset list")?;
 
-        println!("GET {} {}", request.url(), request.status());
+        log::debug!("GET {} {}", request.url(), request.status());
         if request.status() != 200 {
             break;
         }
 
-        let json_full = request.text()?;
-        let metadata: serde_json::Value = serde_json::from_str(&json_full)?;
+        let json_full = request.text().with_context(|| "requesting the ambientCG asset list")?;
+        let metadata: serde_json::Value =
+            serde_json::from_str(&json_full).with_context(|| "parsing the ambientCG asset list")?;
 
         let found_assets = metadata
             .as_object()
@@ -57,74 +97,169 @@ pub fn download_ambientcg(
             .ok_or(ApplicationError::InvalidMetadata)?;
 
         if found_assets.is_empty() {
+            listing_complete = true;
             break;
         }
 
+        // The total is reported with every page, when it's missing the bar grows one page at a time.
+        let total_assets = metadata.get("numberOfResults").and_then(|f| f.as_u64());
+        if request_offset == 0 {
+            log::info!("DOWNLOAD {} assets", total_assets.unwrap_or(found_assets.len() as u64));
+        }
+
         request_offset += found_assets.len();
+        progress.set_length(total_assets.unwrap_or(request_offset as u64));
 
         for asset in found_assets {
             if let Some(asset_id) = asset.get("assetId").and_then(|f| f.as_str()) {
-                if let Some(downloads) = asset
-                    .get("downloadFolders")
-                    .and_then(|f| f.get("default"))
-                    .and_then(|f| f.get("downloadFiletypeCategories"))
-                    .and_then(|f| f.get("zip"))
-                    .and_then(|f| f.get("downloads"))
-                    .and_then(|f| f.as_array())
-                {
-                    for download_type in &download_types {
-                        if let Some(download) =
-                            downloads
-                                .iter()
-                                .find(|download| match download.get("attribute").and_then(|f| f.as_str()) {
-                                    Some(attribute) => attribute == download_type,
-                                    None => false,
-                                })
-                        {
-                            if let Some(download_link) = download.get("fullDownloadPath").and_then(|f| f.as_str()) {
-                                let zip_path = download_cache_path.join(asset_id).with_extension("zip");
-                                if force_download || !zip_path.exists() {
-                                    match client.get(download_link).send() {
-                                        Ok(download_data) => {
-                                            println!("GET {}", download_link);
-                                            use std::io::Write;
-
-                                            let mut cursor = Cursor::new(download_data.bytes()?);
-                                            let mut file = BufWriter::new(File::create(&temp_file_path)?);
-                                            copy(&mut cursor, &mut file)?;
-                                            file.flush()?;
-                                            rename(&temp_file_path, &zip_path)?;
-                                        }
-                                        Err(e) => println!("ERR {} {:?}", asset_id, e),
-                                    }
-                                }
-
-                                let json_path = metadata_path.join(asset_id).with_extension("json");
-                                if !json_path.exists() {
-                                    write(
-                                        &temp_file_path,
-                                        format!(
-                                            concat!(
-                                                "{{\n",
-                                                " \"category\": \"{}\",\n",
-                                                " \"type\": \"{}\",\n",
-                                                " \"method\": \"{}\"\n",
-                                                "}}"
-                                            ),
-                                            asset.get("category").and_then(|f| f.as_str()).unwrap_or("null"),
-                                            asset.get("dataType").and_then(|f| f.as_str()).unwrap_or("null"),
-                                            asset.get("creationMethod").and_then(|f| f.as_str()).unwrap_or("null")
-                                        ),
-                                    )?;
-                                    rename(&temp_file_path, &json_path)?;
-                                }
+                listed_assets.push(asset_id.to_owned());
+                let result = download_single_asset(
+                    manifest,
+                    &client,
+                    asset,
+                    asset_id,
+                    force_download,
+                    download_cache_path,
+                    download_types,
+                );
+                failures.handle("download", asset_id, result.map(|bytes| progress.add_bytes(bytes)))?;
+                progress.inc(asset_id);
+            }
+        }
+    }
+
+    progress.finish();
+
+    // Only a complete listing tells which cached assets were removed upstream or are no longer requested.
+    if listing_complete {
+        let listing_path = download_cache_path.join(LISTING_FILE_NAME);
+        let temp_file_path = download_cache_path.join("mtldownload.tmp");
+        write(
+            &temp_file_path,
+            serde_json::to_string_pretty(&serde_json::json!({
+                "types": download_settings.types_request,
+                "assets": listed_assets,
+            }))?,
+        )
+        .with_context(|| format!("writing {}", temp_file_path.display()))?;
+        rename(&temp_file_path, &listing_path).with_context(|| format!("writing {}", listing_path.display()))?;
+    }
+
+    Ok(())
+}
+
+fn download_single_asset(
+    manifest: &BuildManifest,
+    client: &Client,
+    asset: &serde_json::Value,
+    asset_id: &str,
+    force_download: bool,
+    download_cache_path: &std::path::Path,
+    download_types: &[String],
+) -> Result<u64, ApplicationError> {
+    let temp_file_path = download_cache_path.join("mtldownload.tmp");
+    let metadata_path = download_cache_path.join(".mtld");
+
+    if let Some(downloads) = asset
+        .get("downloadFolders")
+        .and_then(|f| f.get("default"))
+        .and_then(|f| f.get("downloadFiletypeCategories"))
+        .and_then(|f| f.get("zip"))
+        .and_then(|f| f.get("downloads"))
+        .and_then(|f| f.as_array())
+    {
+        let json_path = metadata_path.join(asset_id).with_extension("json");
+        // All ambientCG assets are released under CC0, the API doesn't report a license per asset.
+        let asset_metadata = serde_json::to_string_pretty(&serde_json::json!({
+            "category": asset.get("category").and_then(|f| f.as_str()).unwrap_or("null"),
+            "type": asset.get("dataType").and_then(|f| f.as_str()).unwrap_or("null"),
+            "method": asset.get("creationMethod").and_then(|f| f.as_str()).unwrap_or("null"),
+            "tags": asset.get("tags").and_then(|f| f.as_array()).cloned().unwrap_or_default(),
+            "source": "ambientCG",
+            "url": asset
+                .get("shortLink")
+                .and_then(|f| f.as_str())
+                .map(|f| f.to_owned())
+                .unwrap_or_else(|| format!("https://ambientcg.com/view?id={}", asset_id)),
+            "license": "CC0-1.0",
+        }))?;
+        let json_input_hash = manifest
+            .hasher("metadata", OUTPUT_VERSION)
+            .setting("json", &asset_metadata)
+            .finish();
+
+        // Every download type is tried in order, the asset fails only when none of them could be downloaded.
+        let mut last_error = None;
+        let mut downloaded_bytes = 0;
+        for download_type in download_types {
+            if let Some(download) =
+                downloads
+                    .iter()
+                    .find(|download| match download.get("attribute").and_then(|f| f.as_str()) {
+                        Some(attribute) => attribute == download_type,
+                        None => false,
+                    })
+            {
+                if let Some(download_link) = download.get("fullDownloadPath").and_then(|f| f.as_str()) {
+                    let zip_path = download_cache_path.join(asset_id).with_extension("zip");
+                    let zip_input_hash = manifest
+                        .hasher("download", OUTPUT_VERSION)
+                        .setting("link", download_link)
+                        .finish();
+
+                    // Archives downloaded before the manifest existed are kept as they are.
+                    if zip_path.exists() && !manifest.is_recorded(&zip_path) {
+                        manifest.record(&zip_path, &zip_input_hash);
+                    }
+
+                    if force_download || !manifest.is_up_to_date(&zip_path, &zip_input_hash) {
+                        match client.get(download_link).send().and_then(|r| r.error_for_status()) {
+                            Ok(download_data) => {
+                                log::debug!("GET {}", download_link);
+                                use std::io::Write;
+
+                                let download_bytes = download_data
+                                    .bytes()
+                                    .with_context(|| format!("downloading {}", download_link))?;
+                                downloaded_bytes = download_bytes.len() as u64;
+
+                                let mut cursor = Cursor::new(download_bytes);
+                                File::create(&temp_file_path)
+                                    .map(BufWriter::new)
+                                    .and_then(|mut file| {
+                                        copy(&mut cursor, &mut file)?;
+                                        file.flush()
+                                    })
+                                    .with_context(|| format!("writing {}", temp_file_path.display()))?;
+                                rename(&temp_file_path, &zip_path)
+                                    .with_context(|| format!("writing {}", zip_path.display()))?;
+                                manifest.record(&zip_path, &zip_input_hash);
+                            }
+                            Err(e) => {
+                                log::warn!("{} {}: {}", asset_id, download_link, e);
+                                last_error = Some((download_link, e));
+                                continue;
                             }
                         }
                     }
+
+                    if force_download || !manifest.is_up_to_date(&json_path, &json_input_hash) {
+                        write(&temp_file_path, &asset_metadata)
+                            .with_context(|| format!("writing {}", temp_file_path.display()))?;
+                        rename(&temp_file_path, &json_path)
+                            .with_context(|| format!("writing {}", json_path.display()))?;
+                        manifest.record(&json_path, &json_input_hash);
+                    }
+
+                    return Ok(downloaded_bytes);
                 }
             }
         }
+
+        if let Some((download_link, err)) = last_error {
+            return Err(err).with_context(|| format!("downloading {}", download_link));
+        }
     }
 
-    Ok(())
+    Ok(0)
 }
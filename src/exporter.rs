@@ -0,0 +1,61 @@
+// Copyright (c) 2021 Kyrylo Bazhenov
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::BTreeSet;
+use std::path::*;
+
+use crate::application_error::*;
+use crate::failures::*;
+use crate::logging::*;
+use crate::material::*;
+
+/// Turns packed materials into assets of a game engine or another tool.
+pub trait Exporter {
+    /// Stage name used in logs, progress bars and failure reports, e.g. `unity`.
+    fn stage(&self) -> &'static str;
+
+    /// Exports one material from its folder in the pack cache.
+    fn export_material(&self, material_path: &Path) -> Result<MaterialOutputs, ApplicationError>;
+
+    /// Writes state shared by all materials, e.g. the build manifest. Called once after the last material, also when
+    /// a material failed.
+    fn finish(&self) -> Result<(), ApplicationError>;
+}
+
+/// Exports all materials in the pack cache, or only `only_materials`.
+pub fn export(
+    exporter: &dyn Exporter,
+    pack_cache_path: &Path,
+    only_materials: Option<&BTreeSet<String>>,
+    failures: &FailureLog,
+) -> Result<(), ApplicationError> {
+    let material_paths = material_paths(pack_cache_path, only_materials)?;
+    let result = export_all(exporter, failures, &material_paths);
+    exporter.finish()?;
+    result
+}
+
+fn export_all(
+    exporter: &dyn Exporter,
+    failures: &FailureLog,
+    material_paths: &[PathBuf],
+) -> Result<(), ApplicationError> {
+    let stage = exporter.stage();
+    log::info!("{} {} materials", stage.to_uppercase(), material_paths.len());
+    let progress = StageProgress::new(stage, material_paths.len() as u64);
+
+    for material_path in material_paths {
+        let material_name = material_path.file_name().unwrap_or_default().to_string_lossy();
+        failures.handle(
+            stage,
+            &material_name,
+            exporter.export_material(material_path).map(|_| ()),
+        )?;
+        progress.inc(&material_name);
+    }
+
+    progress.finish();
+    Ok(())
+}
@@ -0,0 +1,110 @@
+// Copyright (c) 2021 Kyrylo Bazhenov
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::fs::*;
+use std::path::*;
+use std::sync::Mutex;
+
+use crate::application_error::*;
+
+pub struct AssetFailure {
+    pub stage: &'static str,
+    pub asset: String,
+    pub error: String,
+}
+
+/// Collects per-asset errors of all stages.
+///
+/// Without `keep_going` the first error is returned with the stage and asset name as context and stops the run.
+pub struct FailureLog {
+    keep_going: bool,
+    failures: Mutex<Vec<AssetFailure>>,
+}
+
+impl FailureLog {
+    pub fn new(keep_going: bool) -> Self {
+        Self {
+            keep_going,
+            failures: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn handle(
+        &self,
+        stage: &'static str,
+        asset: &str,
+        result: Result<(), ApplicationError>,
+    ) -> Result<(), ApplicationError> {
+        match result {
+            Err(err) if self.keep_going => {
+                log::error!("{} {}: {}", stage, asset, err.chain());
+                self.failures.lock().unwrap().push(AssetFailure {
+                    stage,
+                    asset: asset.to_owned(),
+                    error: err.chain(),
+                });
+                Ok(())
+            }
+            result => result.with_context(|| format!("{} {}", stage, asset)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.failures.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Prints a table of failed assets. JSON logs already contain every failure as an error event.
+    pub fn print_summary(&self) {
+        let failures = self.failures.lock().unwrap();
+        if failures.is_empty() || crate::logging::is_json() {
+            return;
+        }
+
+        let stage_width = failures
+            .iter()
+            .map(|f| f.stage.len())
+            .chain(Some(5))
+            .max()
+            .unwrap_or_default();
+        let asset_width = failures
+            .iter()
+            .map(|f| f.asset.len())
+            .chain(Some(5))
+            .max()
+            .unwrap_or_default();
+
+        println!();
+        println!("{} asset(s) failed:", failures.len());
+        println!("{:stage_width$}  {:asset_width$}  Error", "Stage", "Asset");
+        for failure in failures.iter() {
+            println!(
+                "{:stage_width$}  {:asset_width$}  {}",
+                failure.stage, failure.asset, failure.error
+            );
+        }
+    }
+
+    pub fn write_json(&self, report_path: &Path) -> Result<(), ApplicationError> {
+        let failures = self.failures.lock().unwrap();
+        let report = failures
+            .iter()
+            .map(|failure| {
+                serde_json::json!({
+                    "stage": failure.stage,
+                    "asset": failure.asset,
+                    "error": failure.error,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        write(report_path, serde_json::to_string_pretty(&report)?)
+            .with_context(|| format!("writing {}", report_path.display()))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,273 @@
+// Copyright (c) 2021 Kyrylo Bazhenov
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use handlebars::Handlebars;
+use image::imageops::FilterType;
+use image::*;
+use rayon::iter::*;
+use std::collections::BTreeSet;
+use std::fs::*;
+use std::path::*;
+
+use crate::application_error::*;
+use crate::failures::*;
+use crate::index::*;
+use crate::logging::*;
+use crate::manifest::*;
+use crate::pack;
+use crate::template;
+
+/// Output version of gallery thumbnails, bump when they are rendered differently.
+const OUTPUT_VERSION: u32 = 1;
+
+/// Thumbnails shown for every material: pack cache file suffix and the name used in templates.
+const GALLERY_THUMBNAILS: [(&str, &str); 4] = [
+    ("preview", "preview"),
+    ("A", "albedo"),
+    ("N", "normal"),
+    ("MODR", "packed"),
+];
+
+pub struct GallerySettings {
+    pub thumbnail_size: u32,
+    pub index_template: PathBuf,
+    pub material_template: PathBuf,
+}
+
+/// Renders a static HTML gallery of the pack cache into `gallery_path`.
+///
+/// Pages only reference thumbnails next to them, so the gallery can be opened from `file://` or copied elsewhere.
+pub fn generate_gallery(
+    index_paths: &IndexPaths,
+    gallery_path: &Path,
+    gallery_settings: &GallerySettings,
+    failures: &FailureLog,
+) -> Result<(), ApplicationError> {
+    create_dir_all(gallery_path.join("thumbnails"))
+        .with_context(|| format!("creating {}", gallery_path.join("thumbnails").display()))?;
+    create_dir_all(gallery_path.join("materials"))
+        .with_context(|| format!("creating {}", gallery_path.join("materials").display()))?;
+
+    // Material.json values are user data, so unlike exporter templates the gallery escapes HTML.
+    let mut templates = template::create_registry();
+    templates.register_escape_fn(handlebars::html_escape);
+    let index_template = template::register_template(&mut templates, &gallery_settings.index_template)?;
+    let material_template = template::register_template(&mut templates, &gallery_settings.material_template)?;
+
+    let mut material_paths = read_dir(&index_paths.pack_cache_path)
+        .with_context(|| format!("reading {}", index_paths.pack_cache_path.display()))?
+        .filter_map(|f| f.ok())
+        .map(|f| f.path())
+        .filter(|f| f.join("Material.json").is_file())
+        .collect::<Vec<_>>();
+    material_paths.sort();
+
+    log::info!("GALLERY {} materials", material_paths.len());
+    let progress = StageProgress::new("gallery", material_paths.len() as u64);
+
+    let manifest = BuildManifest::load(gallery_path)?;
+    let result = material_paths
+        .par_iter()
+        .map(|material_path| {
+            let material_name = material_path.file_name().unwrap_or_default().to_string_lossy();
+
+            let mut entry = None;
+            failures.handle(
+                "gallery",
+                &material_name,
+                generate_material_page(
+                    &manifest,
+                    &templates,
+                    &material_template,
+                    index_paths,
+                    gallery_path,
+                    gallery_settings,
+                    &material_name,
+                )
+                .map(|f| entry = Some(f)),
+            )?;
+            progress.inc(&material_name);
+            Ok(entry)
+        })
+        .collect::<Result<Vec<_>, ApplicationError>>();
+    manifest.save()?;
+
+    let materials = result?.into_iter().flatten().collect::<Vec<_>>();
+    progress.finish();
+
+    let categories = materials
+        .iter()
+        .filter_map(|f| f.get("category").and_then(|f| f.as_str()))
+        .collect::<BTreeSet<_>>();
+
+    write_page(
+        &templates,
+        &index_template,
+        &serde_json::json!({
+            "categories": categories,
+            "maps": MATERIAL_MAPS,
+            "materials": materials,
+        }),
+        &gallery_path.join("index.html"),
+    )
+}
+
+/// Writes thumbnails and the detail page of one material, and returns its entry for the index page.
+fn generate_material_page(
+    manifest: &BuildManifest,
+    templates: &Handlebars,
+    material_template: &str,
+    index_paths: &IndexPaths,
+    gallery_path: &Path,
+    gallery_settings: &GallerySettings,
+    material_name: &str,
+) -> Result<serde_json::Value, ApplicationError> {
+    let material_path = index_paths.pack_cache_path.join(material_name);
+    let material_json = material_path.join("Material.json");
+    let material: serde_json::Value = serde_json::from_str(
+        &read_to_string(&material_json).with_context(|| format!("reading {}", material_json.display()))?,
+    )
+    .with_context(|| format!("parsing {}", material_json.display()))?;
+
+    let mut thumbnails = serde_json::Map::new();
+    for (suffix, thumbnail_name) in GALLERY_THUMBNAILS {
+        let source_path = material_path.join(format!("{}_{}.png", material_name, suffix));
+        if !source_path.exists() {
+            continue;
+        }
+
+        let thumbnail_file_name = format!("{}_{}.png", material_name, suffix);
+        let thumbnail_path = gallery_path.join("thumbnails").join(&thumbnail_file_name);
+        let input_hash = manifest
+            .hasher("gallery", OUTPUT_VERSION)
+            .file(&source_path)?
+            .setting("thumbnail_size", gallery_settings.thumbnail_size)
+            .finish();
+        if !manifest.is_up_to_date(&thumbnail_path, &input_hash) {
+            // Packed maps store roughness in alpha, showing it as transparency would hide the other channels.
+            let source_image = pack::load_png(&source_path)?;
+            let thumbnail_image = if suffix == "MODR" {
+                DynamicImage::ImageRgb8(source_image.into_rgb8())
+            } else {
+                source_image
+            };
+
+            let temp_file_path = gallery_path
+                .join("thumbnails")
+                .join(format!("{}.tmp", thumbnail_file_name));
+            thumbnail_image
+                .resize(
+                    gallery_settings.thumbnail_size,
+                    gallery_settings.thumbnail_size,
+                    FilterType::Triangle,
+                )
+                .save_with_format(&temp_file_path, ImageFormat::Png)
+                .with_context(|| format!("writing {}", temp_file_path.display()))?;
+            rename(&temp_file_path, &thumbnail_path)
+                .with_context(|| format!("writing {}", thumbnail_path.display()))?;
+            manifest.record(&thumbnail_path, &input_hash);
+        }
+
+        thumbnails.insert(
+            thumbnail_name.to_owned(),
+            serde_json::json!(format!("thumbnails/{}", thumbnail_file_name)),
+        );
+    }
+
+    let maps = MATERIAL_MAPS
+        .iter()
+        .filter(|map| material.get(**map).and_then(|f| f.as_bool()).unwrap_or_default())
+        .collect::<Vec<_>>();
+
+    let fields = material
+        .as_object()
+        .map(|f| {
+            f.iter()
+                .map(|(key, value)| {
+                    let value = match value {
+                        serde_json::Value::String(value) => value.clone(),
+                        serde_json::Value::Array(values) => values
+                            .iter()
+                            .map(|f| f.as_str().map(|f| f.to_owned()).unwrap_or_else(|| f.to_string()))
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                        value => value.to_string(),
+                    };
+                    serde_json::json!({ "key": key, "value": value })
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    let mut outputs = Vec::new();
+    for (stage, cache_path) in [
+        ("download", &index_paths.download_cache_path),
+        ("pack", &index_paths.pack_cache_path),
+        ("basisu", &index_paths.basisu_cache_path),
+        ("dds", &index_paths.dds_cache_path),
+        ("unity", &index_paths.unity_cache_path),
+    ] {
+        let mut files = Vec::new();
+        for output_path in stage_output_paths(stage, cache_path, material_name) {
+            files.push(serde_json::json!({
+                "path": output_path.display().to_string(),
+                "size": metadata(&output_path)
+                    .with_context(|| format!("reading {}", output_path.display()))?
+                    .len(),
+            }));
+        }
+
+        if !files.is_empty() {
+            outputs.push(serde_json::json!({ "stage": stage, "files": files }));
+        }
+    }
+
+    let entry = serde_json::json!({
+        "name": material_name,
+        "category": material.get("category").and_then(|f| f.as_str()),
+        "type": material.get("type").and_then(|f| f.as_str()),
+        "maps": maps,
+        "thumbnails": thumbnails,
+        "page": format!("materials/{}.html", material_name),
+    });
+
+    // Detail pages live one folder deeper than the thumbnails.
+    let page_thumbnails = thumbnails
+        .iter()
+        .map(|(name, path)| {
+            (
+                name.clone(),
+                serde_json::json!(format!("../{}", path.as_str().unwrap_or_default())),
+            )
+        })
+        .collect::<serde_json::Map<_, _>>();
+
+    write_page(
+        templates,
+        material_template,
+        &serde_json::json!({
+            "material": entry,
+            "fields": fields,
+            "thumbnails": page_thumbnails,
+            "outputs": outputs,
+        }),
+        &gallery_path.join("materials").join(format!("{}.html", material_name)),
+    )?;
+
+    Ok(entry)
+}
+
+fn write_page(
+    templates: &Handlebars,
+    template_name: &str,
+    context: &serde_json::Value,
+    page_path: &Path,
+) -> Result<(), ApplicationError> {
+    let temp_file_path = page_path.with_extension("tmp");
+    write(&temp_file_path, templates.render(template_name, context)?)
+        .with_context(|| format!("writing {}", temp_file_path.display()))?;
+    rename(&temp_file_path, page_path).with_context(|| format!("writing {}", page_path.display()))?;
+    Ok(())
+}
@@ -0,0 +1,638 @@
+// Copyright (c) 2021 Kyrylo Bazhenov
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use indicatif::HumanBytes;
+use rayon::iter::*;
+use std::collections::BTreeSet;
+use std::fs::*;
+use std::path::*;
+
+use crate::application_error::*;
+use crate::basisu::{self, CompressSettings};
+use crate::dds;
+use crate::download;
+use crate::failures::*;
+use crate::index::*;
+use crate::manifest::*;
+use crate::pack::{self, AtlasRegion, MaterialSource};
+use crate::preview::{self, PreviewSettings};
+use crate::unity::{self, UnitySettings};
+
+/// Settings the outputs are compared against, outputs built with other settings are outdated. Packed materials are
+/// compared against the settings they were packed with instead, so that materials packed with other flags are kept.
+pub struct GcSettings<'a> {
+    pub sources: &'a [MaterialSource],
+    pub preview_settings: &'a PreviewSettings,
+    pub compress_settings: &'a CompressSettings,
+    pub unity_settings: &'a UnitySettings,
+    pub dry_run: bool,
+}
+
+/// A file or folder to be removed from a cache folder.
+struct Garbage {
+    cache_path: PathBuf,
+    path: PathBuf,
+    reason: &'static str,
+}
+
+/// Removes temporary files left behind by interrupted runs, outputs whose source no longer exists and outputs that
+/// were built from other inputs or settings than the current ones.
+pub fn collect_garbage(
+    index_paths: &IndexPaths,
+    blender_cache_path: &Path,
+    gc_settings: &GcSettings,
+    failures: &FailureLog,
+) -> Result<(), ApplicationError> {
+    let download_cache_path = &index_paths.download_cache_path;
+    let pack_cache_path = &index_paths.pack_cache_path;
+    let basisu_cache_path = &index_paths.basisu_cache_path;
+    let dds_cache_path = &index_paths.dds_cache_path;
+    let unity_cache_path = &index_paths.unity_cache_path;
+
+    let cache_paths = [
+        download_cache_path.as_path(),
+        pack_cache_path,
+        basisu_cache_path,
+        dds_cache_path,
+        unity_cache_path,
+        blender_cache_path,
+    ];
+
+    let mut garbage = Vec::new();
+    for cache_path in cache_paths {
+        let mut file_paths = Vec::new();
+        list_files(cache_path, &mut file_paths)?;
+        garbage.extend(
+            file_paths
+                .into_iter()
+                .filter(|f| f.extension().is_some_and(|e| e == "tmp"))
+                .map(|path| Garbage {
+                    cache_path: cache_path.to_owned(),
+                    path,
+                    reason: "temporary file",
+                }),
+        );
+    }
+
+    // Archives that the last complete listing didn't return were removed upstream or are filtered out by
+    // --download-types, without a listing every archive is kept.
+    let listing_path = download_cache_path.join(download::LISTING_FILE_NAME);
+    let listed_assets = if listing_path.exists() {
+        let listing: serde_json::Value = serde_json::from_str(
+            &read_to_string(&listing_path).with_context(|| format!("reading {}", listing_path.display()))?,
+        )
+        .with_context(|| format!("parsing {}", listing_path.display()))?;
+        listing.get("assets").and_then(|f| f.as_array()).map(|assets| {
+            assets
+                .iter()
+                .filter_map(|f| f.as_str())
+                .map(|f| f.to_owned())
+                .collect::<BTreeSet<_>>()
+        })
+    } else {
+        None
+    };
+
+    for zip_path in list_dir(download_cache_path)?
+        .into_iter()
+        .filter(|f| f.is_file() && f.extension().is_some_and(|e| e == "zip"))
+    {
+        let asset_id = zip_path.file_stem().unwrap_or_default().to_string_lossy();
+        if listed_assets
+            .as_ref()
+            .is_some_and(|listed_assets| !listed_assets.contains(asset_id.as_ref()))
+        {
+            for path in [
+                zip_path.clone(),
+                download_cache_path.join(".mtld").join(format!("{}.json", asset_id)),
+            ] {
+                if path.exists() {
+                    garbage.push(Garbage {
+                        cache_path: download_cache_path.to_owned(),
+                        path,
+                        reason: "not listed",
+                    });
+                }
+            }
+        }
+    }
+
+    for json_path in list_dir(&download_cache_path.join(".mtld"))? {
+        let asset_id = json_path.file_stem().unwrap_or_default().to_string_lossy();
+        if !download_cache_path.join(format!("{}.zip", asset_id)).exists() {
+            garbage.push(Garbage {
+                cache_path: download_cache_path.to_owned(),
+                path: json_path,
+                reason: "no source",
+            });
+        }
+    }
+
+    let download_manifest = BuildManifest::load(download_cache_path)?;
+    let pack_manifest = BuildManifest::load(pack_cache_path)?;
+    let basisu_manifest = BuildManifest::load(basisu_cache_path)?;
+    let dds_manifest = BuildManifest::load(dds_cache_path)?;
+    let unity_manifest = BuildManifest::load(unity_cache_path)?;
+
+    collect_stage(
+        failures,
+        &mut garbage,
+        "gc pack",
+        pack_cache_path,
+        |material_name| {
+            let material = read_material(&pack_cache_path.join(material_name));
+            let source_name = packed_source_name(material.as_ref(), material_name);
+            match gc_settings.sources.iter().find(|source| source.name() == source_name) {
+                Some(source) => Some(source.path().to_owned()),
+                // Only downloaded materials are known to be orphaned, a local source might just not be passed to gc.
+                None if is_downloaded(material.as_ref()) => {
+                    Some(download_cache_path.join(format!("{}.zip", source_name)))
+                }
+                None => None,
+            }
+        },
+        |material_path, source_path| {
+            if let Some(source) = gc_settings.sources.iter().find(|source| source.path() == source_path) {
+                let material = read_material(material_path);
+                let region = material
+                    .as_ref()
+                    .and_then(|f| f.get("region"))
+                    .filter(|f| !f.is_null())
+                    .map(AtlasRegion::from_json)
+                    .transpose()?;
+                if pack::is_outdated(&pack_manifest, source, region.as_ref(), pack_cache_path)? {
+                    return Ok(vec![material_path.to_owned()]);
+                }
+            }
+
+            let mut outdated_paths = Vec::new();
+            if preview::is_outdated(&pack_manifest, material_path, gc_settings.preview_settings)? {
+                let material_name = material_path.file_name().unwrap_or_default().to_string_lossy();
+                outdated_paths.push(material_path.join(format!("{}_preview.png", material_name)));
+            }
+            Ok(outdated_paths)
+        },
+    )?;
+
+    collect_stage(
+        failures,
+        &mut garbage,
+        "gc basisu",
+        basisu_cache_path,
+        |material_name| Some(pack_cache_path.join(material_name)),
+        |material_path, pack_material_path| {
+            if basisu::is_outdated(
+                &basisu_manifest,
+                pack_material_path,
+                basisu_cache_path,
+                gc_settings.compress_settings,
+            )? {
+                Ok(vec![material_path.to_owned()])
+            } else {
+                Ok(Vec::new())
+            }
+        },
+    )?;
+
+    collect_stage(
+        failures,
+        &mut garbage,
+        "gc dds",
+        dds_cache_path,
+        |material_name| Some(pack_cache_path.join(material_name)),
+        |material_path, pack_material_path| {
+            if dds::is_outdated(&dds_manifest, pack_material_path, dds_cache_path)? {
+                Ok(vec![material_path.to_owned()])
+            } else {
+                Ok(Vec::new())
+            }
+        },
+    )?;
+
+    collect_stage(
+        failures,
+        &mut garbage,
+        "gc unity",
+        unity_cache_path,
+        |material_name| Some(pack_cache_path.join(material_name)),
+        |_, pack_material_path| {
+            unity::outdated_outputs(
+                &unity_manifest,
+                pack_material_path,
+                unity_cache_path,
+                gc_settings.unity_settings,
+            )
+        },
+    )?;
+
+    // Files inside removed folders go away with their folder.
+    let garbage_paths = garbage.iter().map(|f| f.path.clone()).collect::<BTreeSet<_>>();
+    garbage.retain(|f| !f.path.ancestors().skip(1).any(|path| garbage_paths.contains(path)));
+
+    log::info!("GC {} outputs", garbage.len());
+
+    let mut removed_size = 0;
+    // Sizes are summarized per cache folder, in stage order.
+    let mut cache_sizes = cache_paths
+        .iter()
+        .map(|cache_path| (cache_path.to_path_buf(), 0, 0))
+        .collect::<Vec<_>>();
+    for garbage in &garbage {
+        let size = path_size(&garbage.path)?;
+        if gc_settings.dry_run {
+            log::info!(
+                "would remove {} ({}, {})",
+                garbage.path.display(),
+                garbage.reason,
+                HumanBytes(size)
+            );
+        } else {
+            if garbage.path.is_dir() {
+                remove_dir_all(&garbage.path)
+            } else {
+                remove_file(&garbage.path)
+            }
+            .with_context(|| format!("removing {}", garbage.path.display()))?;
+            log::info!(
+                "removed {} ({}, {})",
+                garbage.path.display(),
+                garbage.reason,
+                HumanBytes(size)
+            );
+        }
+
+        removed_size += size;
+        if let Some((_, count, cache_size)) = cache_sizes
+            .iter_mut()
+            .find(|(cache_path, _, _)| *cache_path == garbage.cache_path)
+        {
+            *count += 1;
+            *cache_size += size;
+        }
+    }
+    cache_sizes.retain(|(_, count, _)| *count > 0);
+
+    if !gc_settings.dry_run {
+        for (cache_path, manifest) in [
+            (download_cache_path, &download_manifest),
+            (pack_cache_path, &pack_manifest),
+            (basisu_cache_path, &basisu_manifest),
+            (dds_cache_path, &dds_manifest),
+            (unity_cache_path, &unity_manifest),
+        ] {
+            if cache_path.is_dir() {
+                manifest.prune();
+                manifest.save()?;
+            }
+        }
+    }
+
+    print_summary(&cache_sizes, garbage.len(), removed_size, gc_settings.dry_run);
+    Ok(())
+}
+
+/// Reads `Material.json` of a packed material, none when it's missing or unreadable.
+fn read_material(material_path: &Path) -> Option<serde_json::Value> {
+    read_to_string(material_path.join("Material.json"))
+        .ok()
+        .and_then(|f| serde_json::from_str(&f).ok())
+}
+
+/// Name of the source a packed material was packed from, the atlas for atlas regions.
+fn packed_source_name(material: Option<&serde_json::Value>, material_name: &str) -> String {
+    let name = |field: &str| material.and_then(|f| f.get(field)).and_then(|f| f.as_str());
+    name("atlas").or(name("name")).unwrap_or(material_name).to_owned()
+}
+
+/// Returns true when a packed material was packed from an archive downloaded from ambientCG.
+fn is_downloaded(material: Option<&serde_json::Value>) -> bool {
+    material.is_some_and(|material| material.get("source").and_then(|f| f.as_str()) == Some("ambientCG"))
+}
+
+/// Checks the folders of one material at a time in the cache folder of a stage. Materials whose source is missing or
+/// about to be removed are removed as a whole, otherwise `outdated_outputs` lists what to remove. Materials without a
+/// known source are kept as they are.
+fn collect_stage(
+    failures: &FailureLog,
+    garbage: &mut Vec<Garbage>,
+    stage: &'static str,
+    cache_path: &Path,
+    source_path: impl Fn(&str) -> Option<PathBuf>,
+    outdated_outputs: impl Fn(&Path, &Path) -> Result<Vec<PathBuf>, ApplicationError> + Sync,
+) -> Result<(), ApplicationError> {
+    let removed_paths = garbage.iter().map(|f| f.path.clone()).collect::<BTreeSet<_>>();
+    let material_paths = list_dir(cache_path)?
+        .into_iter()
+        .filter(|f| f.is_dir())
+        .filter_map(|material_path| {
+            let material_name = material_path.file_name().unwrap_or_default().to_string_lossy();
+            match source_path(&material_name) {
+                Some(source_path) => Some((material_path, source_path)),
+                None => {
+                    log::warn!(
+                        "keeping {}, its local source is unknown without the --source folder it was packed from",
+                        material_path.display()
+                    );
+                    None
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let stage_garbage = material_paths
+        .par_iter()
+        .map(|(material_path, source_path)| {
+            if !source_path.exists() || removed_paths.contains(source_path) {
+                return Ok(vec![Garbage {
+                    cache_path: cache_path.to_owned(),
+                    path: material_path.clone(),
+                    reason: "no source",
+                }]);
+            }
+
+            let material_name = material_path.file_name().unwrap_or_default().to_string_lossy();
+            let mut outdated_paths = Vec::new();
+            failures.handle(
+                stage,
+                &material_name,
+                outdated_outputs(material_path, source_path).map(|f| outdated_paths = f),
+            )?;
+            Ok(outdated_paths
+                .into_iter()
+                .map(|path| Garbage {
+                    cache_path: cache_path.to_owned(),
+                    path,
+                    reason: "outdated",
+                })
+                .collect::<Vec<_>>())
+        })
+        .collect::<Result<Vec<_>, ApplicationError>>()?;
+
+    garbage.extend(stage_garbage.into_iter().flatten());
+    Ok(())
+}
+
+fn print_summary(cache_sizes: &[(PathBuf, usize, u64)], count: usize, size: u64, dry_run: bool) {
+    let verb = if dry_run { "Would remove" } else { "Removed" };
+    if crate::logging::is_json() {
+        log::info!("{} {} outputs, {}", verb, count, HumanBytes(size));
+        return;
+    }
+
+    let cache_width = cache_sizes
+        .iter()
+        .map(|(cache_path, _, _)| cache_path.display().to_string().len())
+        .chain(Some(5))
+        .max()
+        .unwrap_or_default();
+
+    println!();
+    println!("{} {} outputs, {}", verb, count, HumanBytes(size));
+    if !cache_sizes.is_empty() {
+        println!("{:cache_width$}  {:>7}  Size", "Cache", "Outputs");
+        for (cache_path, count, size) in cache_sizes {
+            println!(
+                "{:cache_width$}  {:>7}  {}",
+                cache_path.display().to_string(),
+                count,
+                HumanBytes(*size)
+            );
+        }
+    }
+}
+
+/// Lists entries of a folder, a missing folder has no entries.
+fn list_dir(dir_path: &Path) -> Result<Vec<PathBuf>, ApplicationError> {
+    if !dir_path.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut paths = read_dir(dir_path)
+        .with_context(|| format!("reading {}", dir_path.display()))?
+        .filter_map(|f| f.ok())
+        .map(|f| f.path())
+        .collect::<Vec<_>>();
+    paths.sort();
+    Ok(paths)
+}
+
+fn list_files(dir_path: &Path, file_paths: &mut Vec<PathBuf>) -> Result<(), ApplicationError> {
+    for path in list_dir(dir_path)? {
+        if path.is_dir() {
+            list_files(&path, file_paths)?;
+        } else {
+            file_paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn path_size(path: &Path) -> Result<u64, ApplicationError> {
+    if path.is_dir() {
+        let mut file_paths = Vec::new();
+        list_files(path, &mut file_paths)?;
+        file_paths
+            .iter()
+            .map(|f| Ok(metadata(f).with_context(|| format!("reading {}", f.display()))?.len()))
+            .sum()
+    } else {
+        Ok(metadata(path)
+            .with_context(|| format!("reading {}", path.display()))?
+            .len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bake::NormalKernel;
+    use crate::pack::{NormalMapType, PackSettings};
+    use crate::preview::PreviewShape;
+    use crate::unity::UnityPipeline;
+    use std::collections::BTreeMap;
+
+    struct TestCaches {
+        root_path: PathBuf,
+        index_paths: IndexPaths,
+    }
+
+    impl TestCaches {
+        fn new(name: &str) -> Self {
+            let root_path = std::env::temp_dir().join(format!("mtld-gc-{}-{}", std::process::id(), name));
+            if root_path.exists() {
+                remove_dir_all(&root_path).unwrap();
+            }
+            create_dir_all(&root_path).unwrap();
+            Self {
+                index_paths: IndexPaths {
+                    download_cache_path: root_path.join("download"),
+                    pack_cache_path: root_path.join("pack"),
+                    basisu_cache_path: root_path.join("basisu"),
+                    dds_cache_path: root_path.join("dds"),
+                    unity_cache_path: root_path.join("unity"),
+                },
+                root_path,
+            }
+        }
+
+        fn write_material(&self, material_name: &str, material: serde_json::Value) -> PathBuf {
+            let material_path = self.index_paths.pack_cache_path.join(material_name);
+            create_dir_all(&material_path).unwrap();
+            write(material_path.join("Material.json"), material.to_string()).unwrap();
+            material_path
+        }
+
+        fn collect_garbage(&self, sources: &[MaterialSource]) {
+            collect_garbage(
+                &self.index_paths,
+                &self.root_path.join("blender"),
+                &GcSettings {
+                    sources,
+                    preview_settings: &PreviewSettings {
+                        shape: PreviewShape::Sphere,
+                        size: 256,
+                        normal_map_type: NormalMapType::OpenGL,
+                    },
+                    compress_settings: &CompressSettings {
+                        uastc_level: 2,
+                        rdo_quality: 0.0,
+                    },
+                    unity_settings: &UnitySettings {
+                        pipeline: UnityPipeline::BuiltIn,
+                        asset_path: String::new(),
+                        texture_template: PathBuf::new(),
+                        material_template: None,
+                        terrain_categories: Vec::new(),
+                        terrain_tile_size: 1.0,
+                        terrain_layer_template: PathBuf::new(),
+                    },
+                    dry_run: false,
+                },
+                &FailureLog::new(false),
+            )
+            .unwrap();
+        }
+    }
+
+    impl Drop for TestCaches {
+        fn drop(&mut self) {
+            let _ = remove_dir_all(&self.root_path);
+        }
+    }
+
+    fn write_color(source_path: &Path, size: u32) {
+        create_dir_all(source_path).unwrap();
+        let name = source_path.file_name().unwrap().to_string_lossy();
+        image::RgbImage::from_pixel(size, size, image::Rgb([200, 100, 50]))
+            .save(source_path.join(format!("{}_Color.png", name)))
+            .unwrap();
+    }
+
+    #[test]
+    fn keeps_local_material_without_source_folder() {
+        let caches = TestCaches::new("local");
+        let material_path = caches.write_material(
+            "Brick009",
+            serde_json::json!({ "name": "Brick009", "atlas": null, "region": null, "source": null }),
+        );
+
+        caches.collect_garbage(&[]);
+        assert!(material_path.exists());
+    }
+
+    #[test]
+    fn removes_downloaded_material_without_archive() {
+        let caches = TestCaches::new("orphaned");
+        let material_path = caches.write_material(
+            "Ground001",
+            serde_json::json!({ "name": "Ground001", "atlas": null, "region": null, "source": "ambientCG" }),
+        );
+
+        caches.collect_garbage(&[]);
+        assert!(!material_path.exists());
+    }
+
+    #[test]
+    fn resolves_atlas_regions_through_their_atlas() {
+        let caches = TestCaches::new("atlas");
+        let download_cache_path = &caches.index_paths.download_cache_path;
+        create_dir_all(download_cache_path).unwrap();
+        let atlas_path = download_cache_path.join("Leaf003.zip");
+        write(&atlas_path, b"").unwrap();
+
+        let region = AtlasRegion {
+            name: "Top".to_owned(),
+            x: 0.0,
+            y: 0.0,
+            width: 0.5,
+            height: 0.5,
+        };
+        let kept_path = caches.write_material(
+            "Leaf003_Top",
+            serde_json::json!({
+                "name": "Leaf003_Top",
+                "atlas": "Leaf003",
+                "region": region.to_json(),
+                "source": "ambientCG",
+            }),
+        );
+        let removed_path = caches.write_material(
+            "Leaf004_Top",
+            serde_json::json!({
+                "name": "Leaf004_Top",
+                "atlas": "Leaf004",
+                "region": region.to_json(),
+                "source": "ambientCG",
+            }),
+        );
+
+        // Without --pack-atlas-regions the region is still found through the atlas named in its Material.json.
+        caches.collect_garbage(&[MaterialSource::Zip(atlas_path)]);
+        assert!(kept_path.exists());
+        assert!(!removed_path.exists());
+    }
+
+    #[test]
+    fn compares_packs_against_recorded_settings() {
+        let caches = TestCaches::new("settings");
+        let source_path = caches.root_path.join("materials").join("Plaster001");
+        write_color(&source_path, 16);
+        let sources = [MaterialSource::Dir(source_path.clone())];
+
+        let pack_settings = PackSettings {
+            normal_map_type: NormalMapType::Direct3D,
+            target_width: 8,
+            target_height: 8,
+            generate_normals: false,
+            normal_strength: 1.0,
+            normal_kernel: NormalKernel::Sobel,
+            bake_ao: false,
+            ao_strength: 1.0,
+            ao_radius: 8.0,
+            spec_gloss: false,
+            dilate_colors: true,
+            atlas_regions: BTreeMap::new(),
+        };
+        pack::pack(
+            &sources,
+            false,
+            true,
+            &caches.index_paths.pack_cache_path,
+            &pack_settings,
+            &FailureLog::new(false),
+        )
+        .unwrap();
+        let material_path = caches.index_paths.pack_cache_path.join("Plaster001");
+        assert!(material_path.join("Material.json").exists());
+
+        // Settings other than the defaults passed to gc don't make the material outdated.
+        caches.collect_garbage(&sources);
+        assert!(material_path.exists());
+
+        write_color(&source_path, 32);
+        caches.collect_garbage(&sources);
+        assert!(!material_path.exists());
+    }
+}
@@ -0,0 +1,248 @@
+// Copyright (c) 2021 Kyrylo Bazhenov
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use rayon::iter::*;
+use std::fs::*;
+use std::path::*;
+
+use crate::application_error::*;
+use crate::failures::*;
+use crate::logging::*;
+use crate::manifest::*;
+
+pub const MATERIAL_MAPS: [&str; 11] = [
+    "albedo",
+    "opacity",
+    "normal",
+    "metalness",
+    "roughness",
+    "ao",
+    "displacement",
+    "emission",
+    "subsurface",
+    "translucency",
+    "specular",
+];
+
+/// Cache folders whose outputs are listed for every material.
+pub struct IndexPaths {
+    pub download_cache_path: PathBuf,
+    pub pack_cache_path: PathBuf,
+    pub basisu_cache_path: PathBuf,
+    pub dds_cache_path: PathBuf,
+    pub unity_cache_path: PathBuf,
+}
+
+/// Writes `library.json` listing every packed material with its metadata and output files of every stage, and
+/// optionally the same list as CSV.
+pub fn generate_index(
+    index_paths: &IndexPaths,
+    index_path: &Path,
+    csv_path: Option<&Path>,
+    failures: &FailureLog,
+) -> Result<(), ApplicationError> {
+    let mut material_paths = read_dir(&index_paths.pack_cache_path)
+        .with_context(|| format!("reading {}", index_paths.pack_cache_path.display()))?
+        .filter_map(|f| f.ok())
+        .map(|f| f.path())
+        .filter(|f| f.join("Material.json").is_file())
+        .collect::<Vec<_>>();
+    material_paths.sort();
+
+    log::info!("INDEX {} materials", material_paths.len());
+    let progress = StageProgress::new("index", material_paths.len() as u64);
+
+    // File hashes are cached in the build manifests, they are read but never written here.
+    let stages = [
+        ("download", &index_paths.download_cache_path),
+        ("pack", &index_paths.pack_cache_path),
+        ("basisu", &index_paths.basisu_cache_path),
+        ("dds", &index_paths.dds_cache_path),
+        ("unity", &index_paths.unity_cache_path),
+    ]
+    .into_iter()
+    .map(|(stage, cache_path)| Ok((stage, cache_path.as_path(), BuildManifest::load(cache_path)?)))
+    .collect::<Result<Vec<_>, ApplicationError>>()?;
+
+    let entries = material_paths
+        .par_iter()
+        .map(|material_path| {
+            let material_name = material_path.file_name().unwrap_or_default().to_string_lossy();
+
+            let mut entry = None;
+            failures.handle(
+                "index",
+                &material_name,
+                index_material(&stages, &index_paths.pack_cache_path, &material_name).map(|f| entry = Some(f)),
+            )?;
+            progress.inc(&material_name);
+            Ok(entry)
+        })
+        .collect::<Result<Vec<_>, ApplicationError>>()?
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+
+    progress.finish();
+
+    let library = serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "materials": entries,
+    });
+    write_file(index_path, &serde_json::to_string_pretty(&library)?)?;
+
+    if let Some(csv_path) = csv_path {
+        write_file(csv_path, &library_csv(&entries))?;
+    }
+
+    Ok(())
+}
+
+fn index_material(
+    stages: &[(&'static str, &Path, BuildManifest)],
+    pack_cache_path: &Path,
+    material_name: &str,
+) -> Result<serde_json::Value, ApplicationError> {
+    let material_path = pack_cache_path.join(material_name);
+    let material_json = material_path.join("Material.json");
+    let material: serde_json::Value = serde_json::from_str(
+        &read_to_string(&material_json).with_context(|| format!("reading {}", material_json.display()))?,
+    )
+    .with_context(|| format!("parsing {}", material_json.display()))?;
+
+    let maps = MATERIAL_MAPS
+        .iter()
+        .filter(|map| material.get(**map).and_then(|f| f.as_bool()).unwrap_or_default())
+        .collect::<Vec<_>>();
+
+    let resolution = ["A", "N", "MODR"]
+        .iter()
+        .map(|suffix| material_path.join(format!("{}_{}.png", material_name, suffix)))
+        .find(|image_path| image_path.exists())
+        .map(|image_path| image::image_dimensions(&image_path))
+        .transpose()?
+        .map(|(width, height)| serde_json::json!({ "width": width, "height": height }));
+
+    let mut outputs = serde_json::Map::new();
+    for (stage, cache_path, manifest) in stages {
+        let output_paths = stage_output_paths(stage, cache_path, material_name);
+
+        let mut stage_outputs = Vec::new();
+        for output_path in &output_paths {
+            stage_outputs.push(serde_json::json!({
+                "path": output_path.display().to_string(),
+                "size": metadata(output_path)
+                    .with_context(|| format!("reading {}", output_path.display()))?
+                    .len(),
+                "hash": manifest.hash_file(output_path)?,
+            }));
+        }
+        outputs.insert(stage.to_string(), serde_json::Value::Array(stage_outputs));
+    }
+
+    Ok(serde_json::json!({
+        "name": material_name,
+        "category": material.get("category"),
+        "type": material.get("type"),
+        "method": material.get("method"),
+        "tags": material.get("tags").cloned().unwrap_or_else(|| serde_json::json!([])),
+        "source": material.get("source"),
+        "url": material.get("url"),
+        "license": material.get("license"),
+        "maps": maps,
+        "resolution": resolution,
+        "outputs": outputs,
+    }))
+}
+
+/// Lists output files of one material in the cache folder of a stage.
+pub fn stage_output_paths(stage: &str, cache_path: &Path, material_name: &str) -> Vec<PathBuf> {
+    let output_paths = match stage {
+        "download" => vec![
+            cache_path.join(format!("{}.zip", material_name)),
+            cache_path.join(".mtld").join(format!("{}.json", material_name)),
+        ],
+        _ => {
+            // Stages that didn't run yet have no folder for the material.
+            match read_dir(cache_path.join(material_name)) {
+                Ok(directory_contents) => {
+                    let mut stage_paths = directory_contents
+                        .filter_map(|f| f.ok())
+                        .map(|f| f.path())
+                        .collect::<Vec<_>>();
+                    stage_paths.sort();
+                    stage_paths
+                }
+                Err(_) => Vec::new(),
+            }
+        }
+    };
+
+    output_paths.into_iter().filter(|f| f.is_file()).collect()
+}
+
+/// One row per material, lists are joined with `;` and outputs are summarized as a file count and total size.
+fn library_csv(entries: &[serde_json::Value]) -> String {
+    let mut csv = String::from("name,category,type,tags,source,url,license,maps,width,height,files,size\n");
+    for entry in entries {
+        let text = |key: &str| entry.get(key).and_then(|f| f.as_str()).unwrap_or_default().to_owned();
+        let list = |key: &str| {
+            entry
+                .get(key)
+                .and_then(|f| f.as_array())
+                .map(|f| f.iter().filter_map(|f| f.as_str()).collect::<Vec<_>>().join(";"))
+                .unwrap_or_default()
+        };
+        let dimension = |key: &str| {
+            entry
+                .get("resolution")
+                .and_then(|f| f.get(key))
+                .map(|f| f.to_string())
+                .unwrap_or_default()
+        };
+        let files = entry
+            .get("outputs")
+            .and_then(|f| f.as_object())
+            .map(|f| f.values().filter_map(|f| f.as_array()).flatten().collect::<Vec<_>>())
+            .unwrap_or_default();
+        let size = files
+            .iter()
+            .filter_map(|f| f.get("size").and_then(|f| f.as_u64()))
+            .sum::<u64>();
+
+        let row = [
+            text("name"),
+            text("category"),
+            text("type"),
+            list("tags"),
+            text("source"),
+            text("url"),
+            text("license"),
+            list("maps"),
+            dimension("width"),
+            dimension("height"),
+            files.len().to_string(),
+            size.to_string(),
+        ];
+        csv.push_str(&row.iter().map(|f| csv_field(f)).collect::<Vec<_>>().join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+fn write_file(file_path: &Path, contents: &str) -> Result<(), ApplicationError> {
+    let temp_file_path = file_path.with_extension("tmp");
+    write(&temp_file_path, contents).with_context(|| format!("writing {}", temp_file_path.display()))?;
+    rename(&temp_file_path, file_path).with_context(|| format!("writing {}", file_path.display()))?;
+    Ok(())
+}
@@ -0,0 +1,85 @@
+// Copyright (c) 2021 Kyrylo Bazhenov
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Material pipeline behind the `mtld` command line tool: packs ambientCG-style materials into three textures,
+//! compresses them with Basis Universal and exports them to game engines.
+//!
+//! Every stage has a function that processes one material and returns the [`MaterialOutputs`] it built or kept, and a
+//! function that runs the stage over a whole cache folder with progress bars and failure reporting, which is what the
+//! command line tool uses. Stages skip outputs that a [`BuildManifest`] records as built from the same inputs and
+//! settings; call [`BuildManifest::save`] after the last material.
+//!
+//! ```no_run
+//! use mtld::*;
+//! use std::path::Path;
+//!
+//! # fn main() -> Result<(), ApplicationError> {
+//! let pack_cache_path = Path::new(".mtld-pack-cache");
+//! let manifest = BuildManifest::load(pack_cache_path)?;
+//! let settings = PackSettings {
+//!     normal_map_type: NormalMapType::OpenGL,
+//!     target_width: 1024,
+//!     target_height: 1024,
+//!     generate_normals: true,
+//!     normal_strength: 4.0,
+//!     normal_kernel: NormalKernel::Sobel,
+//!     bake_ao: true,
+//!     ao_strength: 4.0,
+//!     ao_radius: 16.0,
+//!     spec_gloss: false,
+//!     dilate_colors: true,
+//!     atlas_regions: Default::default(),
+//! };
+//! let outputs = pack::pack_material(
+//!     &manifest,
+//!     &MaterialSource::Dir("Bricks001".into()),
+//!     pack_cache_path,
+//!     &settings,
+//!     false,
+//! )?;
+//! manifest.save()?;
+//!
+//! for path in outputs.built() {
+//!     println!("{}", path.display());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod application_error;
+pub mod array;
+pub mod bake;
+pub mod basisu;
+pub mod bcn;
+pub mod blender;
+pub mod color;
+pub mod dds;
+pub mod dilate;
+pub mod download;
+pub mod exporter;
+pub mod failures;
+pub mod gallery;
+pub mod gc;
+pub mod index;
+pub mod logging;
+pub mod manifest;
+pub mod material;
+pub mod mips;
+pub mod pack;
+pub mod preview;
+mod template;
+pub mod unity;
+pub mod watch;
+pub mod workflow;
+
+pub use application_error::ApplicationError;
+pub use bake::NormalKernel;
+pub use basisu::CompressSettings;
+pub use exporter::Exporter;
+pub use manifest::BuildManifest;
+pub use material::{MaterialOutput, MaterialOutputs, OutputStatus};
+pub use pack::{AtlasRegion, MaterialSource, NormalMapType, PackSettings};
+pub use preview::PreviewSettings;
+pub use unity::{UnityExporter, UnitySettings};
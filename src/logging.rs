@@ -0,0 +1,168 @@
+// Copyright (c) 2021 Kyrylo Bazhenov
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::io::{stdout, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::application_error::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ApplicationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(ApplicationError::InvalidParameter(s.to_string())),
+        }
+    }
+}
+
+/// Prints log records above the progress bars, or as one JSON object per line.
+struct Logger {
+    format: LogFormat,
+    progress: MultiProgress,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Installs the logger. Progress bars are only drawn for text output at the default or a more verbose level.
+pub fn init(level: LevelFilter, format: LogFormat) {
+    let progress = MultiProgress::new();
+    if format == LogFormat::Json || level < LevelFilter::Info {
+        progress.set_draw_target(ProgressDrawTarget::hidden());
+    }
+
+    let logger = LOGGER.get_or_init(|| Logger { format, progress });
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+pub fn is_json() -> bool {
+    LOGGER.get().is_some_and(|logger| logger.format == LogFormat::Json)
+}
+
+impl Log for Logger {
+    // Dependencies only report warnings and errors, their debug output drowns mtld's own messages.
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+            && (metadata.level() <= Level::Warn || metadata.target().starts_with(env!("CARGO_PKG_NAME")))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        match self.format {
+            LogFormat::Text => self.progress.suspend(|| match record.level() {
+                Level::Info => println!("{}", record.args()),
+                level => eprintln!("{} {}", level, record.args()),
+            }),
+            LogFormat::Json => emit_event(serde_json::json!({
+                "event": "log",
+                "level": record.level().as_str().to_lowercase(),
+                "message": record.args().to_string(),
+            })),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn emit_event(mut event: serde_json::Value) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs_f64())
+        .unwrap_or_default();
+    event["timestamp"] = serde_json::json!(timestamp);
+
+    // A closed pipe, e.g. `mtld ... | head`, shouldn't abort the run.
+    let _ = writeln!(stdout().lock(), "{}", event);
+}
+
+/// Tracks items processed by one stage, drawn as a progress bar or reported as `progress` JSON events.
+pub struct StageProgress {
+    stage: &'static str,
+    bar: ProgressBar,
+    bytes: AtomicU64,
+}
+
+impl StageProgress {
+    pub fn new(stage: &'static str, total: u64) -> Self {
+        let bar = ProgressBar::new(total).with_style(
+            ProgressStyle::with_template("{prefix:>8} [{elapsed_precise}] {wide_bar} {pos}/{len} {msg} ETA {eta}")
+                .unwrap(),
+        );
+        let bar = match LOGGER.get() {
+            Some(logger) => logger.progress.add(bar),
+            None => bar,
+        };
+        bar.set_prefix(stage.to_uppercase());
+
+        if is_json() {
+            emit_event(serde_json::json!({ "event": "stage_started", "stage": stage, "total": total }));
+        }
+
+        Self {
+            stage,
+            bar,
+            bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Updates the total when it is only known after the stage started, e.g. from the first page of the asset list.
+    pub fn set_length(&self, total: u64) {
+        self.bar.set_length(total);
+    }
+
+    /// Counts downloaded bytes, shown next to the item count.
+    pub fn add_bytes(&self, bytes: u64) {
+        let bytes = self.bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.bar.set_message(HumanBytes(bytes).to_string());
+    }
+
+    /// Marks one asset as processed, regardless of whether it was rebuilt, skipped or failed.
+    pub fn inc(&self, asset: &str) {
+        self.bar.inc(1);
+
+        if is_json() {
+            emit_event(serde_json::json!({
+                "event": "progress",
+                "stage": self.stage,
+                "asset": asset,
+                "done": self.bar.position(),
+                "total": self.bar.length(),
+                "bytes": self.bytes.load(Ordering::Relaxed),
+            }));
+        }
+    }
+
+    pub fn finish(self) {
+        self.bar.finish();
+
+        if is_json() {
+            emit_event(serde_json::json!({
+                "event": "stage_finished",
+                "stage": self.stage,
+                "done": self.bar.position(),
+                "bytes": self.bytes.load(Ordering::Relaxed),
+                "elapsed": self.bar.elapsed().as_secs_f64(),
+            }));
+        }
+    }
+}
@@ -5,25 +5,64 @@
 
 use structopt::*;
 
+use std::collections::BTreeSet;
 use std::path::*;
 use std::str::FromStr;
+use std::time::Duration;
 
-mod application_error;
-mod basisu;
-mod download;
-mod pack;
-mod unity;
-
-use crate::application_error::*;
+use mtld::application_error::*;
+use mtld::exporter;
+use mtld::failures::*;
+use mtld::{array, bake, basisu, blender, dds, download, gallery, gc, index, logging, pack, preview, unity, watch};
 
 #[derive(Debug, StructOpt)]
 struct CommandLineOptions {
+    #[structopt(subcommand)]
+    command: Option<Command>,
+
+    #[structopt(
+        short = "v",
+        long = "verbose",
+        help = "Prints more messages, use -vv to also print trace messages",
+        parse(from_occurrences)
+    )]
+    verbose: u8,
+
+    #[structopt(
+        short = "q",
+        long = "quiet",
+        help = "Prints only warnings and errors and hides progress bars, use -qq to print only errors",
+        parse(from_occurrences)
+    )]
+    quiet: u8,
+
+    #[structopt(
+        long = "log-format",
+        help = "Log output format: text or json, json prints one event object per line",
+        default_value = "text"
+    )]
+    log_format: logging::LogFormat,
+
+    #[structopt(
+        long = "keep-going",
+        help = "Keeps processing other assets when an asset fails and reports all failures at the end"
+    )]
+    keep_going: bool,
+
+    #[structopt(
+        long = "failure-report",
+        help = "File where the failure report is written in --keep-going mode",
+        default_value = "mtld-failures.json",
+        parse(from_os_str)
+    )]
+    failure_report_path: PathBuf,
+
     #[structopt(long = "download", help = "Downloads .zip files")]
     download: bool,
 
     #[structopt(
         long = "force-download",
-        help = "Forces download even when .zip files exist in the cache"
+        help = "Forces download even when .zip files in the cache are up to date"
     )]
     force_download: bool,
 
@@ -70,10 +109,17 @@ struct CommandLineOptions {
     )]
     request_limit: u32,
 
+    #[structopt(
+        long = "source",
+        help = "Local folder with material folders or .zip files to pack in addition to downloaded files, can be repeated",
+        parse(from_os_str)
+    )]
+    source_paths: Vec<PathBuf>,
+
     #[structopt(long = "pack", help = "Packs downloaded files")]
     pack: bool,
 
-    #[structopt(long = "force-pack", help = "Force packing even when files exist")]
+    #[structopt(long = "force-pack", help = "Force packing even when packed files are up to date")]
     force_pack: bool,
 
     #[structopt(long = "pack-single-threaded", help = "Don't use multi threading for packing")]
@@ -92,7 +138,7 @@ struct CommandLineOptions {
         help = "Normal map type to use for packing",
         default_value = "OpenGL"
     )]
-    pack_normal_map_type: crate::pack::NormalMapType,
+    pack_normal_map_type: pack::NormalMapType,
 
     #[structopt(long = "pack-target-width", help = "Packed image width", default_value = "1024")]
     pack_target_width: u32,
@@ -100,12 +146,94 @@ struct CommandLineOptions {
     #[structopt(long = "pack-target-height", help = "Packed image height", default_value = "1024")]
     pack_target_height: u32,
 
+    #[structopt(
+        long = "pack-generate-normals",
+        help = "Generates normal maps from displacement for materials without a normal map"
+    )]
+    pack_generate_normals: bool,
+
+    #[structopt(
+        long = "pack-normal-strength",
+        help = "Strength of generated normal maps, slopes are scaled as for a 1024 pixels wide texture",
+        default_value = "4"
+    )]
+    pack_normal_strength: f32,
+
+    #[structopt(
+        long = "pack-normal-kernel",
+        help = "Derivative kernel of generated normal maps: Sobel or Scharr",
+        default_value = "Sobel"
+    )]
+    pack_normal_kernel: bake::NormalKernel,
+
+    #[structopt(
+        long = "pack-bake-ao",
+        help = "Bakes ambient occlusion from displacement for materials without an ambient occlusion map"
+    )]
+    pack_bake_ao: bool,
+
+    #[structopt(
+        long = "pack-ao-strength",
+        help = "Height scale of baked ambient occlusion, heights are scaled as for a 1024 pixels wide texture",
+        default_value = "4"
+    )]
+    pack_ao_strength: f32,
+
+    #[structopt(
+        long = "pack-ao-radius",
+        help = "Search radius of baked ambient occlusion in pixels of a 1024 pixels wide texture",
+        default_value = "16"
+    )]
+    pack_ao_radius: f32,
+
+    #[structopt(
+        long = "pack-spec-gloss",
+        help = "Also writes spec/gloss images with the diffuse color, specular color and glossiness"
+    )]
+    pack_spec_gloss: bool,
+
+    #[structopt(
+        long = "pack-dilate-colors",
+        help = "Fills colors of fully transparent albedo texels from visible texels to avoid halos at cutout edges"
+    )]
+    pack_dilate_colors: bool,
+
+    #[structopt(
+        long = "pack-atlas-regions",
+        help = "JSON file with regions of atlases that are also packed as separate materials",
+        parse(from_os_str)
+    )]
+    pack_atlas_regions_path: Option<PathBuf>,
+
+    #[structopt(long = "preview", help = "Renders preview images of packed materials")]
+    preview: bool,
+
+    #[structopt(
+        long = "force-preview",
+        help = "Force rendering preview images even when rendered images are up to date"
+    )]
+    force_preview: bool,
+
+    #[structopt(
+        long = "preview-shape",
+        help = "Shape to render previews on: Sphere or Plane",
+        default_value = "Sphere"
+    )]
+    preview_shape: preview::PreviewShape,
+
+    #[structopt(
+        long = "preview-size",
+        help = "Preview image width and height",
+        default_value = "256"
+    )]
+    preview_size: u32,
+
     #[structopt(long = "basisu", help = "Compresses packed files with Basis Universal")]
     basisu: bool,
 
     #[structopt(
         long = "force-basisu",
-        help = "Force compressing with Basis Universal even when files exist"
+        help = "Force compressing with Basis Universal even when compressed files are up to date"
     )]
     force_basisu: bool,
 
@@ -123,12 +251,46 @@ struct CommandLineOptions {
     )]
     basisu_cache_path: PathBuf,
 
+    #[structopt(
+        long = "basisu-uastc-level",
+        help = "UASTC quality level from 0, fastest, to 4, slowest and best",
+        default_value = "3"
+    )]
+    basisu_uastc_level: u32,
+
+    #[structopt(
+        long = "basisu-rdo-quality",
+        help = "Rate distortion optimization quality, lower values compress better with zstd or LZ4, 0 disables it",
+        default_value = "0.5"
+    )]
+    basisu_rdo_quality: f32,
+
+    #[structopt(
+        long = "dds",
+        help = "Compresses packed files to DDS with BC4, BC5 or BC7 and full mip chains"
+    )]
+    dds: bool,
+
+    #[structopt(
+        long = "force-dds",
+        help = "Force compressing to DDS even when compressed files are up to date"
+    )]
+    force_dds: bool,
+
+    #[structopt(
+        long = "dds-cache-path",
+        help = "Folder where compressed DDS files will be stored",
+        default_value = ".mtld-dds-cache",
+        parse(from_os_str)
+    )]
+    dds_cache_path: PathBuf,
+
     #[structopt(long = "unity", help = "Generates Unity3D meta files")]
     unity: bool,
 
     #[structopt(
         long = "force-unity",
-        help = "Force generate Unity3D meta files even when files exist"
+        help = "Force generate Unity3D meta files even when generated files are up to date"
     )]
     force_unity: bool,
 
@@ -148,13 +310,182 @@ struct CommandLineOptions {
     )]
     unity_texture_template: PathBuf,
 
+    #[structopt(
+        long = "unity-pipeline",
+        help = "Unity3D render pipeline to generate materials for: BuiltIn, URP or HDRP",
+        default_value = "URP"
+    )]
+    unity_pipeline: unity::UnityPipeline,
+
     #[structopt(
         long = "unity-material-template",
-        help = "Template file to generate Unity3D material meta files",
-        default_value = "templates/unity_material.template",
+        help = "Template file to generate Unity3D material meta files [default: templates/unity_<pipeline>_material.template]",
         parse(from_os_str)
     )]
-    unity_material_template: PathBuf,
+    unity_material_template: Option<PathBuf>,
+
+    #[structopt(
+        long = "unity-asset-path",
+        help = "Project folder where Unity3D assets are placed when importing a package",
+        default_value = "Assets/mtld"
+    )]
+    unity_asset_path: String,
+
+    #[structopt(
+        long = "unity-terrain-categories",
+        help = "Generates Unity3D terrain layers for materials in these categories, e.g. Ground,Grass,Rock"
+    )]
+    unity_terrain_categories: Option<ArgumentVec>,
+
+    #[structopt(
+        long = "unity-terrain-tile-size",
+        help = "Physical size of one terrain layer tile in meters",
+        default_value = "2"
+    )]
+    unity_terrain_tile_size: f32,
+
+    #[structopt(
+        long = "unity-terrain-layer-template",
+        help = "Template file to generate Unity3D terrain layers",
+        default_value = "templates/unity_terrain_layer.template",
+        parse(from_os_str)
+    )]
+    unity_terrain_layer_template: PathBuf,
+
+    #[structopt(long = "blender", help = "Generates Blender import script and asset catalogs")]
+    blender: bool,
+
+    #[structopt(
+        long = "blender-cache-path",
+        help = "Folder where Blender import script and asset catalogs will be stored",
+        default_value = ".mtld-blender-cache",
+        parse(from_os_str)
+    )]
+    blender_cache_path: PathBuf,
+
+    #[structopt(
+        long = "blender-script-template",
+        help = "Template file to generate Blender import script",
+        default_value = "templates/blender_import.template",
+        parse(from_os_str)
+    )]
+    blender_script_template: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    #[structopt(about = "Exports packed materials")]
+    Export(ExportCommand),
+
+    #[structopt(about = "Writes a machine-readable index of all packed materials")]
+    Index {
+        #[structopt(
+            long = "output",
+            help = "File where the library index will be stored",
+            default_value = "library.json",
+            parse(from_os_str)
+        )]
+        output: PathBuf,
+
+        #[structopt(long = "csv", help = "Also writes the library index as CSV", parse(from_os_str))]
+        csv: Option<PathBuf>,
+    },
+
+    #[structopt(about = "Renders a static HTML gallery of all packed materials")]
+    Gallery {
+        #[structopt(
+            long = "output",
+            help = "Folder where the gallery will be stored",
+            default_value = "mtld-gallery",
+            parse(from_os_str)
+        )]
+        output: PathBuf,
+
+        #[structopt(long = "thumbnail-size", help = "Thumbnail size in pixels", default_value = "256")]
+        thumbnail_size: u32,
+
+        #[structopt(
+            long = "index-template",
+            help = "Template file to generate the gallery index page",
+            default_value = "templates/gallery_index.template",
+            parse(from_os_str)
+        )]
+        index_template: PathBuf,
+
+        #[structopt(
+            long = "material-template",
+            help = "Template file to generate gallery material pages",
+            default_value = "templates/gallery_material.template",
+            parse(from_os_str)
+        )]
+        material_template: PathBuf,
+    },
+
+    #[structopt(about = "Builds texture arrays of albedo, normal and _MODR images with one layer per packed material")]
+    Array {
+        #[structopt(long = "name", help = "Texture array name, also the name of its output folder")]
+        name: String,
+
+        #[structopt(long = "materials", help = "Comma-separated packed materials, in layer order")]
+        materials: ArgumentVec,
+
+        #[structopt(
+            long = "output",
+            help = "Folder where texture arrays will be stored",
+            default_value = ".mtld-array-cache",
+            parse(from_os_str)
+        )]
+        output: PathBuf,
+
+        #[structopt(long = "width", help = "Layer width", default_value = "1024")]
+        width: u32,
+
+        #[structopt(long = "height", help = "Layer height", default_value = "1024")]
+        height: u32,
+
+        #[structopt(
+            long = "unity",
+            help = "Also writes flipbook images with meta files that Unity3D imports as texture arrays"
+        )]
+        unity: bool,
+
+        #[structopt(
+            long = "force",
+            help = "Force building texture arrays even when built files are up to date"
+        )]
+        force: bool,
+    },
+
+    #[structopt(
+        about = "Removes temporary files, outputs without a source and outputs built with other settings from the caches"
+    )]
+    Gc {
+        #[structopt(long = "dry-run", help = "Lists what would be removed without removing anything")]
+        dry_run: bool,
+    },
+
+    #[structopt(about = "Packs, compresses and exports materials again whenever their sources change")]
+    Watch {
+        #[structopt(
+            long = "debounce",
+            help = "Milliseconds without changes to wait for before rebuilding",
+            default_value = "500"
+        )]
+        debounce: u64,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum ExportCommand {
+    #[structopt(about = "Generates Unity3D materials and meta files")]
+    Unity {
+        #[structopt(
+            long = "package",
+            help = "Writes generated assets into a .unitypackage archive",
+            parse(from_os_str)
+        )]
+        package: Option<PathBuf>,
+    },
 }
 
 #[derive(Debug, PartialEq)]
@@ -168,30 +499,238 @@ impl FromStr for ArgumentVec {
     }
 }
 
-fn main() -> Result<(), ApplicationError> {
+fn main() {
     let command_line = CommandLineOptions::from_args();
 
-    if command_line.download || command_line.force_download {
+    let log_level = match command_line.verbose as i32 - command_line.quiet as i32 {
+        i32::MIN..=-2 => log::LevelFilter::Error,
+        -1 => log::LevelFilter::Warn,
+        0 => log::LevelFilter::Info,
+        1 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+    logging::init(log_level, command_line.log_format);
+
+    if let Err(err) = run(command_line) {
+        if logging::is_json() {
+            log::error!("{}", err.chain());
+        } else {
+            eprintln!("error: {}", err);
+
+            let mut source = std::error::Error::source(&err);
+            while let Some(cause) = source {
+                eprintln!("  caused by: {}", cause);
+                source = cause.source();
+            }
+        }
+
+        std::process::exit(1);
+    }
+}
+
+impl CommandLineOptions {
+    fn pack_settings(&self) -> Result<pack::PackSettings, ApplicationError> {
+        Ok(pack::PackSettings {
+            normal_map_type: self.pack_normal_map_type,
+            target_width: self.pack_target_width,
+            target_height: self.pack_target_height,
+            generate_normals: self.pack_generate_normals,
+            normal_strength: self.pack_normal_strength,
+            normal_kernel: self.pack_normal_kernel,
+            bake_ao: self.pack_bake_ao,
+            ao_strength: self.pack_ao_strength,
+            ao_radius: self.pack_ao_radius,
+            spec_gloss: self.pack_spec_gloss,
+            dilate_colors: self.pack_dilate_colors,
+            atlas_regions: match &self.pack_atlas_regions_path {
+                Some(regions_path) => pack::load_atlas_regions(regions_path)?,
+                None => Default::default(),
+            },
+        })
+    }
+
+    fn preview_settings(&self) -> preview::PreviewSettings {
+        preview::PreviewSettings {
+            shape: self.preview_shape,
+            size: self.preview_size,
+            normal_map_type: self.pack_normal_map_type,
+        }
+    }
+
+    fn compress_settings(&self) -> basisu::CompressSettings {
+        basisu::CompressSettings {
+            uastc_level: self.basisu_uastc_level,
+            rdo_quality: self.basisu_rdo_quality,
+        }
+    }
+
+    fn unity_settings(&self) -> unity::UnitySettings {
+        unity::UnitySettings {
+            pipeline: self.unity_pipeline,
+            asset_path: self.unity_asset_path.clone(),
+            texture_template: self.unity_texture_template.clone(),
+            material_template: self.unity_material_template.clone(),
+            terrain_categories: self
+                .unity_terrain_categories
+                .as_ref()
+                .map(|categories| categories.0.clone())
+                .unwrap_or_default(),
+            terrain_tile_size: self.unity_terrain_tile_size,
+            terrain_layer_template: self.unity_terrain_layer_template.clone(),
+        }
+    }
+}
+
+fn run(command_line: CommandLineOptions) -> Result<(), ApplicationError> {
+    if let Some(Command::Watch { debounce }) = &command_line.command {
+        return watch_sources(&command_line, Duration::from_millis(*debounce));
+    }
+
+    let failures = FailureLog::new(command_line.keep_going);
+    run_stages(&command_line, &failures, None)?;
+
+    let index_paths = index::IndexPaths {
+        download_cache_path: command_line.download_cache_path.clone(),
+        pack_cache_path: command_line.pack_cache_path.clone(),
+        basisu_cache_path: command_line.basisu_cache_path.clone(),
+        dds_cache_path: command_line.dds_cache_path.clone(),
+        unity_cache_path: command_line.unity_cache_path.clone(),
+    };
+
+    if let Some(Command::Index { output, csv }) = &command_line.command {
+        index::generate_index(&index_paths, output, csv.as_deref(), &failures)?;
+    }
+
+    if let Some(Command::Gallery {
+        output,
+        thumbnail_size,
+        index_template,
+        material_template,
+    }) = &command_line.command
+    {
+        gallery::generate_gallery(
+            &index_paths,
+            output,
+            &gallery::GallerySettings {
+                thumbnail_size: *thumbnail_size,
+                index_template: index_template.clone(),
+                material_template: material_template.clone(),
+            },
+            &failures,
+        )?;
+    }
+
+    if let Some(Command::Array {
+        name,
+        materials,
+        output,
+        width,
+        height,
+        unity,
+        force,
+    }) = &command_line.command
+    {
+        array::generate_texture_array(
+            &command_line.pack_cache_path,
+            output,
+            name,
+            &materials.0,
+            &array::ArraySettings {
+                width: *width,
+                height: *height,
+                unity_texture_template: unity.then(|| command_line.unity_texture_template.clone()),
+            },
+            *force,
+        )?;
+    }
+
+    if let Some(Command::Gc { dry_run }) = &command_line.command {
+        gc::collect_garbage(
+            &index_paths,
+            &command_line.blender_cache_path,
+            &gc::GcSettings {
+                sources: &pack::collect_sources(&command_line.download_cache_path, &command_line.source_paths)?,
+                preview_settings: &command_line.preview_settings(),
+                compress_settings: &command_line.compress_settings(),
+                unity_settings: &command_line.unity_settings(),
+                dry_run: *dry_run,
+            },
+            &failures,
+        )?;
+    }
+
+    failures.print_summary();
+    if command_line.keep_going {
+        failures.write_json(&command_line.failure_report_path)?;
+    }
+
+    if !failures.is_empty() {
+        return Err(ApplicationError::AssetsFailed(failures.len()));
+    }
+
+    Ok(())
+}
+
+/// Runs the stages selected on the command line, for all materials or only for `only_materials`.
+fn run_stages(
+    command_line: &CommandLineOptions,
+    failures: &FailureLog,
+    only_materials: Option<&BTreeSet<String>>,
+) -> Result<(), ApplicationError> {
+    let pack_settings = command_line.pack_settings()?;
+
+    // Atlas regions are packed as separate materials, later stages process them together with their atlas.
+    let only_materials = only_materials.map(|materials| {
+        materials
+            .iter()
+            .flat_map(|material_name| {
+                let regions = pack_settings.atlas_regions.get(material_name).into_iter().flatten();
+                std::iter::once(material_name.clone())
+                    .chain(regions.map(move |region| format!("{}_{}", material_name, region.name)))
+            })
+            .collect::<BTreeSet<_>>()
+    });
+    let only_materials = only_materials.as_ref();
+
+    // Downloads can't be limited to some materials, watch mode only downloads when it starts.
+    if (command_line.download || command_line.force_download) && only_materials.is_none() {
         download::download_ambientcg(
             command_line.force_download,
             &command_line.download_cache_path,
-            &command_line.download_resolutions.0,
-            &command_line.download_extensions.0,
-            &command_line.download_types,
-            &command_line.user_agent,
-            command_line.request_limit,
+            &download::DownloadSettings {
+                resolutions: command_line.download_resolutions.0.clone(),
+                extensions: command_line.download_extensions.0.clone(),
+                types_request: command_line.download_types.clone(),
+                user_agent: command_line.user_agent.clone(),
+                request_limit: command_line.request_limit,
+            },
+            failures,
         )?;
     }
 
-    if command_line.pack || command_line.force_pack {
+    let watch = matches!(command_line.command, Some(Command::Watch { .. }));
+    if command_line.pack || command_line.force_pack || watch {
+        let sources = pack::collect_sources(&command_line.download_cache_path, &command_line.source_paths)?
+            .into_iter()
+            .filter(|source| only_materials.is_none_or(|f| f.contains(&source.name())))
+            .collect::<Vec<_>>();
         pack::pack(
-            &command_line.download_cache_path,
+            &sources,
             command_line.force_pack,
             command_line.pack_single_threaded,
             &command_line.pack_cache_path,
-            command_line.pack_normal_map_type,
-            command_line.pack_target_width,
-            command_line.pack_target_height,
+            &pack_settings,
+            failures,
+        )?;
+    }
+
+    if command_line.preview || command_line.force_preview {
+        preview::render_previews(
+            &command_line.pack_cache_path,
+            command_line.force_preview,
+            &command_line.preview_settings(),
+            only_materials,
+            failures,
         )?;
     }
 
@@ -201,18 +740,67 @@ fn main() -> Result<(), ApplicationError> {
             command_line.force_basisu,
             command_line.basisu_single_threaded,
             &command_line.basisu_cache_path,
+            &command_line.compress_settings(),
+            only_materials,
+            failures,
         )?;
     }
 
-    if command_line.unity || command_line.force_unity {
-        unity::generate_unity(
+    if command_line.dds || command_line.force_dds {
+        dds::compress_dds(
             &command_line.pack_cache_path,
-            command_line.unity,
+            command_line.force_dds,
+            &command_line.dds_cache_path,
+            only_materials,
+            failures,
+        )?;
+    }
+
+    let export_unity = matches!(command_line.command, Some(Command::Export(ExportCommand::Unity { .. })));
+    if command_line.unity || command_line.force_unity || export_unity {
+        let unity_exporter = unity::UnityExporter::new(
             &command_line.unity_cache_path,
-            &command_line.unity_texture_template,
-            &command_line.unity_material_template,
+            command_line.unity_settings(),
+            command_line.force_unity,
+        )?;
+        exporter::export(&unity_exporter, &command_line.pack_cache_path, only_materials, failures)?;
+    }
+
+    if let Some(Command::Export(ExportCommand::Unity {
+        package: Some(unity_package_path),
+    })) = &command_line.command
+    {
+        unity::package_unity(
+            &command_line.unity_cache_path,
+            unity_package_path,
+            &command_line.unity_asset_path,
+        )?;
+    }
+
+    if command_line.blender {
+        blender::generate_blender(
+            &command_line.pack_cache_path,
+            &command_line.blender_cache_path,
+            &command_line.blender_script_template,
         )?;
     }
 
     Ok(())
 }
+
+/// Builds everything once, then rebuilds materials whose sources change until interrupted. Asset failures are
+/// reported after every rebuild and never stop watching.
+fn watch_sources(command_line: &CommandLineOptions, debounce: Duration) -> Result<(), ApplicationError> {
+    let failures = FailureLog::new(true);
+    run_stages(command_line, &failures, None)?;
+    failures.print_summary();
+
+    let mut watch_paths = vec![command_line.download_cache_path.clone()];
+    watch_paths.extend(command_line.source_paths.iter().cloned());
+    watch::watch(&watch_paths, debounce, |materials| {
+        let failures = FailureLog::new(true);
+        run_stages(command_line, &failures, Some(materials))?;
+        failures.print_summary();
+        Ok(())
+    })
+}
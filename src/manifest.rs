@@ -0,0 +1,266 @@
+// Copyright (c) 2021 Kyrylo Bazhenov
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::BTreeMap;
+use std::fs::*;
+use std::io::{copy, BufReader};
+use std::path::*;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use crate::application_error::*;
+
+const MANIFEST_FILE_NAME: &str = ".mtld-manifest.json";
+
+/// Records, for every output in a cache folder, the hash of the inputs it was built from.
+///
+/// Outputs are rebuilt only when the hash of their current inputs differs from the recorded one, or when the output
+/// itself is missing.
+pub struct BuildManifest {
+    cache_path: PathBuf,
+    outputs: Mutex<BTreeMap<String, String>>,
+    /// Settings an output was built with, for stages that need them to check the output later.
+    settings: Mutex<BTreeMap<String, serde_json::Value>>,
+    files: Mutex<BTreeMap<String, FileHash>>,
+}
+
+#[derive(Clone)]
+struct FileHash {
+    size: u64,
+    modified: u128,
+    hash: String,
+}
+
+impl BuildManifest {
+    pub fn load(cache_path: &Path) -> Result<Self, ApplicationError> {
+        let mut outputs = BTreeMap::new();
+        let mut settings = BTreeMap::new();
+        let mut files = BTreeMap::new();
+
+        let manifest_path = cache_path.join(MANIFEST_FILE_NAME);
+        if manifest_path.exists() {
+            let manifest: serde_json::Value = serde_json::from_str(
+                &read_to_string(&manifest_path).with_context(|| format!("reading {}", manifest_path.display()))?,
+            )
+            .with_context(|| format!("parsing {}", manifest_path.display()))?;
+
+            if let Some(manifest_outputs) = manifest.get("outputs").and_then(|f| f.as_object()) {
+                for (output, input_hash) in manifest_outputs {
+                    if let Some(input_hash) = input_hash.as_str() {
+                        outputs.insert(output.clone(), input_hash.to_owned());
+                    }
+                }
+            }
+
+            if let Some(manifest_settings) = manifest.get("settings").and_then(|f| f.as_object()) {
+                settings.extend(manifest_settings.iter().map(|(output, f)| (output.clone(), f.clone())));
+            }
+
+            if let Some(manifest_files) = manifest.get("files").and_then(|f| f.as_object()) {
+                for (file, file_hash) in manifest_files {
+                    if let (Some(size), Some(modified), Some(hash)) = (
+                        file_hash.get("size").and_then(|f| f.as_u64()),
+                        file_hash
+                            .get("modified")
+                            .and_then(|f| f.as_str())
+                            .and_then(|f| f.parse().ok()),
+                        file_hash.get("hash").and_then(|f| f.as_str()),
+                    ) {
+                        files.insert(
+                            file.clone(),
+                            FileHash {
+                                size,
+                                modified,
+                                hash: hash.to_owned(),
+                            },
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(Self {
+            cache_path: cache_path.to_owned(),
+            outputs: Mutex::new(outputs),
+            settings: Mutex::new(settings),
+            files: Mutex::new(files),
+        })
+    }
+
+    pub fn save(&self) -> Result<(), ApplicationError> {
+        let outputs = self.outputs.lock().unwrap();
+        let settings = self.settings.lock().unwrap();
+        let files = self.files.lock().unwrap();
+
+        let manifest = serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "outputs": *outputs,
+            "settings": *settings,
+            "files": files
+                .iter()
+                .map(|(file, file_hash)| {
+                    (
+                        file.clone(),
+                        serde_json::json!({
+                            "size": file_hash.size,
+                            "modified": file_hash.modified.to_string(),
+                            "hash": file_hash.hash,
+                        }),
+                    )
+                })
+                .collect::<serde_json::Map<_, _>>(),
+        });
+
+        let temp_file_path = self.cache_path.join("mtldmanifest.tmp");
+        write(&temp_file_path, serde_json::to_string_pretty(&manifest)?)
+            .with_context(|| format!("writing {}", temp_file_path.display()))?;
+        rename(&temp_file_path, self.cache_path.join(MANIFEST_FILE_NAME))
+            .with_context(|| format!("writing {}", self.cache_path.join(MANIFEST_FILE_NAME).display()))?;
+        Ok(())
+    }
+
+    /// Returns true when the output exists and was built from inputs with the same hash.
+    pub fn is_up_to_date(&self, output_path: &Path, input_hash: &str) -> bool {
+        output_path.exists()
+            && self
+                .outputs
+                .lock()
+                .unwrap()
+                .get(&self.output_key(output_path))
+                .is_some_and(|recorded_hash| recorded_hash == input_hash)
+    }
+
+    /// Returns true when the output has a recorded input hash, regardless of its value.
+    pub fn is_recorded(&self, output_path: &Path) -> bool {
+        self.outputs.lock().unwrap().contains_key(&self.output_key(output_path))
+    }
+
+    pub fn record(&self, output_path: &Path, input_hash: &str) {
+        self.outputs
+            .lock()
+            .unwrap()
+            .insert(self.output_key(output_path), input_hash.to_owned());
+    }
+
+    /// Records the settings an output was built with, replacing previously recorded ones.
+    pub fn record_settings(&self, output_path: &Path, settings: serde_json::Value) {
+        self.settings
+            .lock()
+            .unwrap()
+            .insert(self.output_key(output_path), settings);
+    }
+
+    /// Returns the settings recorded with `record_settings`, none for outputs built before they were recorded.
+    pub fn recorded_settings(&self, output_path: &Path) -> Option<serde_json::Value> {
+        self.settings
+            .lock()
+            .unwrap()
+            .get(&self.output_key(output_path))
+            .cloned()
+    }
+
+    /// Forgets outputs, their settings and file hashes of files that no longer exist.
+    pub fn prune(&self) {
+        self.outputs
+            .lock()
+            .unwrap()
+            .retain(|output, _| self.cache_path.join(output).exists());
+        self.settings
+            .lock()
+            .unwrap()
+            .retain(|output, _| self.cache_path.join(output).exists());
+        self.files.lock().unwrap().retain(|file, _| Path::new(file).exists());
+    }
+
+    /// Starts hashing inputs of a stage. File hashes are cached by size and modification time.
+    ///
+    /// Every stage passes its own output version, which must be bumped whenever the stage writes different outputs for
+    /// the same inputs and settings. Otherwise outputs built by an older mtld stay up to date after an upgrade.
+    pub fn hasher(&self, stage: &str, output_version: u32) -> InputHasher<'_> {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(env!("CARGO_PKG_VERSION").as_bytes());
+        hasher.update(&[0]);
+        hasher.update(format!("{}@{}", stage, output_version).as_bytes());
+        hasher.update(&[0]);
+
+        InputHasher { manifest: self, hasher }
+    }
+
+    fn output_key(&self, output_path: &Path) -> String {
+        let output_path = output_path.strip_prefix(&self.cache_path).unwrap_or(output_path);
+        output_path
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Returns the hash of a file's contents, reusing the recorded hash while its size and modification time match.
+    pub fn hash_file(&self, file_path: &Path) -> Result<String, ApplicationError> {
+        let file_metadata = metadata(file_path).with_context(|| format!("reading {}", file_path.display()))?;
+        let size = file_metadata.len();
+        let modified = file_metadata
+            .modified()
+            .with_context(|| format!("reading {}", file_path.display()))?
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos())
+            .unwrap_or_default();
+
+        let file_key = file_path.display().to_string();
+        if let Some(file_hash) = self.files.lock().unwrap().get(&file_key) {
+            if file_hash.size == size && file_hash.modified == modified {
+                return Ok(file_hash.hash.clone());
+            }
+        }
+
+        let mut hasher = blake3::Hasher::new();
+        copy(
+            &mut BufReader::new(File::open(file_path).with_context(|| format!("opening {}", file_path.display()))?),
+            &mut hasher,
+        )
+        .with_context(|| format!("reading {}", file_path.display()))?;
+        let hash = hasher.finalize().to_hex().to_string();
+
+        self.files.lock().unwrap().insert(
+            file_key,
+            FileHash {
+                size,
+                modified,
+                hash: hash.clone(),
+            },
+        );
+        Ok(hash)
+    }
+}
+
+#[derive(Clone)]
+pub struct InputHasher<'a> {
+    manifest: &'a BuildManifest,
+    hasher: blake3::Hasher,
+}
+
+impl InputHasher<'_> {
+    pub fn setting(mut self, name: &str, value: impl std::fmt::Display) -> Self {
+        self.hasher.update(format!("{}={}", name, value).as_bytes());
+        self.hasher.update(&[0]);
+        self
+    }
+
+    /// Adds the contents of a file, or a marker when the file doesn't exist.
+    pub fn file(mut self, file_path: &Path) -> Result<Self, ApplicationError> {
+        if file_path.exists() {
+            let file_hash = self.manifest.hash_file(file_path)?;
+            self.hasher.update(file_hash.as_bytes());
+        } else {
+            self.hasher.update(b"missing");
+        }
+        self.hasher.update(&[0]);
+        Ok(self)
+    }
+
+    pub fn finish(self) -> String {
+        self.hasher.finalize().to_hex().to_string()
+    }
+}
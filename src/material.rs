@@ -0,0 +1,84 @@
+// Copyright (c) 2021 Kyrylo Bazhenov
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::BTreeSet;
+use std::fs::*;
+use std::path::*;
+
+use crate::application_error::*;
+
+/// Whether a stage wrote an output file or kept the one in its cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputStatus {
+    /// The file was written because it was missing, outdated or rebuilding was forced.
+    Built,
+    /// The file was built from the same inputs and settings before and was kept.
+    UpToDate,
+}
+
+/// One file a stage produced for a material.
+#[derive(Debug, Clone)]
+pub struct MaterialOutput {
+    pub path: PathBuf,
+    pub status: OutputStatus,
+}
+
+/// Files a stage produced for one material, in the order they were checked.
+///
+/// Every output tracked by the build manifest is listed with its status. Companion files, e.g. Unity3D `.meta` files,
+/// are only listed when they were written.
+#[derive(Debug, Clone)]
+pub struct MaterialOutputs {
+    /// Material name, the name of its folder in the stage cache.
+    pub name: String,
+    pub outputs: Vec<MaterialOutput>,
+}
+
+impl MaterialOutputs {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            outputs: Vec::new(),
+        }
+    }
+
+    /// Paths of files that were written.
+    pub fn built(&self) -> impl Iterator<Item = &Path> {
+        self.outputs
+            .iter()
+            .filter(|f| f.status == OutputStatus::Built)
+            .map(|f| f.path.as_path())
+    }
+
+    pub(crate) fn push(&mut self, path: PathBuf, built: bool) {
+        self.outputs.push(MaterialOutput {
+            path,
+            status: if built {
+                OutputStatus::Built
+            } else {
+                OutputStatus::UpToDate
+            },
+        });
+    }
+}
+
+/// Lists material folders in the pack cache, sorted by name, optionally only those named in `only_materials`.
+pub fn material_paths(
+    pack_cache_path: &Path,
+    only_materials: Option<&BTreeSet<String>>,
+) -> Result<Vec<PathBuf>, ApplicationError> {
+    let mut material_paths = read_dir(pack_cache_path)
+        .with_context(|| format!("reading {}", pack_cache_path.display()))?
+        .filter_map(|f| f.ok())
+        .map(|f| f.path())
+        .filter(|f| f.is_dir())
+        .filter(|f| {
+            only_materials
+                .is_none_or(|materials| materials.contains(&*f.file_name().unwrap_or_default().to_string_lossy()))
+        })
+        .collect::<Vec<_>>();
+    material_paths.sort();
+    Ok(material_paths)
+}
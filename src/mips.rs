@@ -0,0 +1,196 @@
+// Copyright (c) 2021 Kyrylo Bazhenov
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use image::*;
+
+use crate::color::{linear_to_srgb, srgb_to_linear};
+
+/// Alpha that cutout materials are tested against, the `_Cutoff` of the Unity3D material templates.
+pub const ALPHA_TEST_THRESHOLD: f32 = 0.5;
+
+/// How the mip chain of a packed image is filtered.
+#[derive(Debug, Clone, Copy)]
+pub struct MipSettings {
+    /// Colors are averaged in linear space and stored as sRGB, alpha is always linear.
+    pub srgb: bool,
+    /// Averaged tangent-space normals are normalized again.
+    pub renormalize: bool,
+    /// Rescales the alpha of every level so the same fraction of texels passes the alpha test at this threshold as in
+    /// the base level, otherwise cutout materials fade out with distance.
+    pub alpha_test: Option<f32>,
+}
+
+impl MipSettings {
+    /// Albedo of a material, alpha tested when `Material.json` has alpha and the material isn't a decal.
+    pub fn albedo(material: &serde_json::Value) -> Self {
+        let has_alpha = material.get("alpha").and_then(|f| f.as_bool()).unwrap_or_default();
+        // Decals are alpha blended, so their alpha is averaged like any other channel.
+        let decal = material.get("type").and_then(|f| f.as_str()) == Some("Decal");
+        Self {
+            srgb: true,
+            renormalize: false,
+            alpha_test: (has_alpha && !decal).then_some(ALPHA_TEST_THRESHOLD),
+        }
+    }
+
+    pub fn normal() -> Self {
+        Self {
+            srgb: false,
+            renormalize: true,
+            alpha_test: None,
+        }
+    }
+
+    pub fn linear() -> Self {
+        Self {
+            srgb: false,
+            renormalize: false,
+            alpha_test: None,
+        }
+    }
+}
+
+/// Generates the mip chain of an image down to 1x1 with a box filter, the first level is the image itself.
+pub fn generate_mips(image: &RgbaImage, mip_settings: MipSettings) -> Vec<RgbaImage> {
+    let decode = |value: u8, channel: usize| {
+        let value = value as f32 / 255.0;
+        if mip_settings.srgb && channel < 3 {
+            srgb_to_linear(value)
+        } else {
+            value
+        }
+    };
+    let encode = |value: f32, channel: usize| {
+        let value = if mip_settings.srgb && channel < 3 {
+            linear_to_srgb(value)
+        } else {
+            value
+        };
+        (value.clamp(0.0, 1.0) * 255.0).round() as u8
+    };
+
+    let mut level = Rgba32FImage::from_fn(image.width(), image.height(), |x, y| {
+        let pixel = image.get_pixel(x, y);
+        Rgba(std::array::from_fn(|channel| decode(pixel[channel], channel)))
+    });
+    let base_coverage = mip_settings
+        .alpha_test
+        .map(|threshold| alpha_coverage(&level, threshold));
+
+    let mut mips = vec![image.clone()];
+    while level.width() > 1 || level.height() > 1 {
+        level = downsample(&level);
+
+        let alpha_scale = match (mip_settings.alpha_test, base_coverage) {
+            (Some(threshold), Some(base_coverage)) => coverage_alpha_scale(&level, threshold, base_coverage),
+            _ => 1.0,
+        };
+
+        mips.push(RgbaImage::from_fn(level.width(), level.height(), |x, y| {
+            let mut pixel = *level.get_pixel(x, y);
+            if mip_settings.renormalize {
+                let normal = [0, 1, 2].map(|channel| pixel[channel] * 2.0 - 1.0);
+                let length = normal.iter().map(|f| f * f).sum::<f32>().sqrt().max(1e-6);
+                for channel in 0..3 {
+                    pixel[channel] = normal[channel] / length * 0.5 + 0.5;
+                }
+            }
+            pixel[3] *= alpha_scale;
+            Rgba(std::array::from_fn(|channel| encode(pixel[channel], channel)))
+        }));
+    }
+    mips
+}
+
+/// Halves both dimensions, averaging 2x2 texels. Odd edges repeat their last texel.
+fn downsample(level: &Rgba32FImage) -> Rgba32FImage {
+    let (width, height) = (level.width(), level.height());
+    Rgba32FImage::from_fn((width / 2).max(1), (height / 2).max(1), |x, y| {
+        let mut sum = [0.0f32; 4];
+        for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            let texel = level.get_pixel((x * 2 + dx).min(width - 1), (y * 2 + dy).min(height - 1));
+            for channel in 0..4 {
+                sum[channel] += texel[channel];
+            }
+        }
+        Rgba(sum.map(|f| f / 4.0))
+    })
+}
+
+/// Fraction of texels that pass the alpha test.
+fn alpha_coverage(level: &Rgba32FImage, threshold: f32) -> f32 {
+    let passed = level.pixels().filter(|f| f[3] >= threshold).count();
+    passed as f32 / (level.width() * level.height()) as f32
+}
+
+/// Finds the alpha scale that gives a level the coverage of the base level. Every reference above the alpha of the
+/// first texel that has to fail, up to the alpha of the last texel that has to pass, gives that coverage. Alpha is kept
+/// when the threshold already lies in that interval, otherwise it's scaled to move the middle of the interval to the
+/// threshold, so opaque texels don't end up just above it.
+fn coverage_alpha_scale(level: &Rgba32FImage, threshold: f32, base_coverage: f32) -> f32 {
+    let mut alphas = level.pixels().map(|f| f[3]).collect::<Vec<_>>();
+    alphas.sort_by(|a, b| b.total_cmp(a));
+    let passed = ((base_coverage * alphas.len() as f32).round() as usize).min(alphas.len());
+
+    // Texels with the same alpha pass together, so the interval ends at the next lower alpha.
+    let upper = passed.checked_sub(1).map_or(1.0, |index| alphas[index]);
+    let lower = alphas[passed..].iter().copied().find(|f| *f < upper).unwrap_or(0.0);
+    if lower < threshold && threshold <= upper {
+        1.0
+    } else {
+        threshold / ((lower + upper) * 0.5).max(1e-3)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cutout_mips(image: &RgbaImage) -> Vec<RgbaImage> {
+        generate_mips(
+            image,
+            MipSettings {
+                srgb: true,
+                renormalize: false,
+                alpha_test: Some(ALPHA_TEST_THRESHOLD),
+            },
+        )
+    }
+
+    #[test]
+    fn opaque_cutout_keeps_opaque_alpha() {
+        let mips = cutout_mips(&RgbaImage::from_pixel(16, 16, Rgba([90, 120, 40, 255])));
+        for mip in &mips {
+            assert!(mip.pixels().all(|f| f[3] == 255));
+        }
+    }
+
+    #[test]
+    fn mostly_opaque_cutout_keeps_opaque_alpha() {
+        // A transparent 4x4 hole, every level down to 4x4 averages texels that are either all opaque or all transparent.
+        let image = RgbaImage::from_fn(16, 16, |x, y| {
+            let hole = (4..8).contains(&x) && (8..12).contains(&y);
+            Rgba([90, 120, 40, if hole { 0 } else { 255 }])
+        });
+        for mip in &cutout_mips(&image)[..3] {
+            let opaque = mip.pixels().filter(|f| f[3] == 255).count();
+            assert_eq!(opaque, mip.pixels().len() * 15 / 16);
+            assert!(mip.pixels().all(|f| f[3] == 255 || f[3] == 0));
+        }
+    }
+
+    #[test]
+    fn thin_cutout_keeps_coverage() {
+        // Thin opaque lines every fourth column average to 25% alpha two levels down, which still has to pass the test.
+        let image = RgbaImage::from_fn(16, 16, |x, _| Rgba([90, 120, 40, if x % 4 == 0 { 255 } else { 0 }]));
+        for mip in &cutout_mips(&image) {
+            let passed = mip
+                .pixels()
+                .filter(|f| f[3] as f32 / 255.0 >= ALPHA_TEST_THRESHOLD)
+                .count();
+            assert!(passed >= mip.pixels().len() / 4);
+        }
+    }
+}
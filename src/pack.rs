@@ -6,6 +6,7 @@
 use image::{imageops::*, *};
 use itertools::izip;
 use rayon::iter::*;
+use std::collections::BTreeMap;
 use std::fs::*;
 use std::io::{copy, BufReader, Read, Seek};
 use std::path::*;
@@ -13,7 +14,18 @@ use std::str::FromStr;
 use zip::read::*;
 
 use crate::application_error::*;
+use crate::bake::{self, HeightMap, NormalKernel};
+use crate::dilate;
+use crate::failures::*;
+use crate::logging::*;
+use crate::manifest::*;
+use crate::material::*;
+use crate::workflow;
 
+/// Output version of packed textures and Material.json, bump when packing writes different files for the same inputs.
+const OUTPUT_VERSION: u32 = 3;
+
+/// Convention of the green channel of normal maps, ambientCG provides both.
 #[derive(Debug, Clone, Copy)]
 pub enum NormalMapType {
     OpenGL,
@@ -32,271 +44,1014 @@ impl FromStr for NormalMapType {
     }
 }
 
-pub fn pack(
+/// Map packed into a channel of `_MODR.png` and the value the channel has when a material has no data for it.
+#[derive(Debug)]
+pub struct PackedChannel {
+    /// Map name, as in `Material.json`.
+    pub map: &'static str,
+    pub default_value: f32,
+}
+
+impl PackedChannel {
+    pub fn default_byte(&self) -> u8 {
+        (self.default_value * 255.0).round() as u8
+    }
+}
+
+/// Channels of `_MODR.png` in RGBA order. Defaults are neutral: not metallic, not occluded, at mid height and neither
+/// glossy nor rough.
+pub const MATERIAL_PACK_LAYOUT: [PackedChannel; 4] = [
+    PackedChannel {
+        map: "metalness",
+        default_value: 0.0,
+    },
+    PackedChannel {
+        map: "ao",
+        default_value: 1.0,
+    },
+    PackedChannel {
+        map: "displacement",
+        default_value: 0.5,
+    },
+    PackedChannel {
+        map: "roughness",
+        default_value: 0.5,
+    },
+];
+
+/// Channels of `_STS.png` in RGB order: subsurface and translucency weights default to none, specular level defaults
+/// to the 4% reflectance of dielectrics.
+pub const SURFACE_PACK_LAYOUT: [PackedChannel; 3] = [
+    PackedChannel {
+        map: "subsurface",
+        default_value: 0.0,
+    },
+    PackedChannel {
+        map: "translucency",
+        default_value: 0.0,
+    },
+    PackedChannel {
+        map: "specular",
+        default_value: 0.5,
+    },
+];
+
+/// Part of an atlas packed as its own material named `<atlas>_<name>`. Coordinates are relative to the atlas size, with
+/// the origin in the top left corner, so regions don't depend on the downloaded resolution.
+#[derive(Debug, Clone)]
+pub struct AtlasRegion {
+    pub name: String,
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl AtlasRegion {
+    /// Parses a region as written in region descriptions and in `Material.json`.
+    pub fn from_json(region: &serde_json::Value) -> Result<Self, ApplicationError> {
+        let coordinate = |name: &str| {
+            region
+                .get(name)
+                .and_then(|f| f.as_f64())
+                .map(|f| f as f32)
+                .ok_or(ApplicationError::InvalidMetadata)
+        };
+        Ok(Self {
+            name: region
+                .get("name")
+                .and_then(|f| f.as_str())
+                .ok_or(ApplicationError::InvalidMetadata)?
+                .to_owned(),
+            x: coordinate("x")?,
+            y: coordinate("y")?,
+            width: coordinate("width")?,
+            height: coordinate("height")?,
+        })
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "name": self.name,
+            "x": self.x,
+            "y": self.y,
+            "width": self.width,
+            "height": self.height,
+        })
+    }
+}
+
+/// Loads a region description, a JSON object with a list of regions per atlas name, e.g.
+/// `{"Leaf003": [{"name": "Maple", "x": 0, "y": 0, "width": 0.5, "height": 0.5}]}`.
+pub fn load_atlas_regions(regions_path: &Path) -> Result<BTreeMap<String, Vec<AtlasRegion>>, ApplicationError> {
+    let regions_json: serde_json::Value = serde_json::from_str(
+        &read_to_string(regions_path).with_context(|| format!("reading {}", regions_path.display()))?,
+    )
+    .with_context(|| format!("parsing {}", regions_path.display()))?;
+
+    regions_json
+        .as_object()
+        .ok_or(ApplicationError::InvalidMetadata)
+        .and_then(|atlases| {
+            atlases
+                .iter()
+                .map(|(atlas_name, regions)| {
+                    let regions = regions
+                        .as_array()
+                        .ok_or(ApplicationError::InvalidMetadata)?
+                        .iter()
+                        .map(AtlasRegion::from_json)
+                        .collect::<Result<Vec<_>, ApplicationError>>()
+                        .with_context(|| format!("atlas {}", atlas_name))?;
+                    Ok((atlas_name.clone(), regions))
+                })
+                .collect()
+        })
+        .with_context(|| format!("parsing {}", regions_path.display()))
+}
+
+/// How source images are packed, every packed image is resized to the target size.
+pub struct PackSettings {
+    pub normal_map_type: NormalMapType,
+    pub target_width: u32,
+    pub target_height: u32,
+    /// Generates normal maps from displacement for materials without a normal map.
+    pub generate_normals: bool,
+    /// Slope scale of generated normal maps at a 1024 pixel wide texture, scaled with the target width.
+    pub normal_strength: f32,
+    pub normal_kernel: NormalKernel,
+    /// Bakes ambient occlusion from displacement for materials without an ambient occlusion map.
+    pub bake_ao: bool,
+    /// Height scale of baked ambient occlusion, see `normal_strength`.
+    pub ao_strength: f32,
+    /// Horizon search distance of baked ambient occlusion in texels of a 1024 pixel wide texture.
+    pub ao_radius: f32,
+    /// Also writes spec/gloss images, `_D.png` with the diffuse color and `_SG.png` with the specular color and
+    /// glossiness.
+    pub spec_gloss: bool,
+    /// Fills the color of fully transparent albedo texels from visible texels around them, so cutout edges don't get
+    /// halos when filtered or compressed.
+    pub dilate_colors: bool,
+    /// Regions of atlases that are also packed as separate materials, by atlas name.
+    pub atlas_regions: BTreeMap<String, Vec<AtlasRegion>>,
+}
+
+impl PackSettings {
+    /// Settings that affect every packed material, recorded in the build manifest with `Material.json`. Atlas regions
+    /// are recorded in `Material.json` of each region instead.
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "normal_map_type": format!("{:?}", self.normal_map_type),
+            "target_width": self.target_width,
+            "target_height": self.target_height,
+            "generate_normals": self.generate_normals,
+            "normal_strength": self.normal_strength,
+            "normal_kernel": format!("{:?}", self.normal_kernel),
+            "bake_ao": self.bake_ao,
+            "ao_strength": self.ao_strength,
+            "ao_radius": self.ao_radius,
+            "spec_gloss": self.spec_gloss,
+            "dilate_colors": self.dilate_colors,
+        })
+    }
+
+    fn from_json(settings: &serde_json::Value) -> Option<Self> {
+        let number = |name: &str| settings.get(name).and_then(|f| f.as_f64());
+        let flag = |name: &str| settings.get(name).and_then(|f| f.as_bool());
+        Some(Self {
+            normal_map_type: settings.get("normal_map_type")?.as_str()?.parse().ok()?,
+            target_width: number("target_width")? as u32,
+            target_height: number("target_height")? as u32,
+            generate_normals: flag("generate_normals")?,
+            normal_strength: number("normal_strength")? as f32,
+            normal_kernel: settings.get("normal_kernel")?.as_str()?.parse().ok()?,
+            bake_ao: flag("bake_ao")?,
+            ao_strength: number("ao_strength")? as f32,
+            ao_radius: number("ao_radius")? as f32,
+            spec_gloss: flag("spec_gloss")?,
+            dilate_colors: flag("dilate_colors")?,
+            atlas_regions: BTreeMap::new(),
+        })
+    }
+}
+
+/// How source images of one material are cropped and resized to the pack target size.
+struct ImageFit {
+    region: Option<AtlasRegion>,
+    /// Decals and atlases keep their aspect ratio and are centered, the padding repeats the edge texels with zero
+    /// alpha. Tiling materials are stretched to the target size.
+    keep_aspect_ratio: bool,
+    width: u32,
+    height: u32,
+}
+
+impl ImageFit {
+    fn apply(&self, image: DynamicImage) -> DynamicImage {
+        let image = match &self.region {
+            Some(region) => {
+                let (image_width, image_height) = (image.width() as f32, image.height() as f32);
+                let x = (region.x * image_width).round().clamp(0.0, image_width - 1.0);
+                let y = (region.y * image_height).round().clamp(0.0, image_height - 1.0);
+                let width = (region.width * image_width).round().clamp(1.0, image_width - x);
+                let height = (region.height * image_height).round().clamp(1.0, image_height - y);
+                image.crop_imm(x as u32, y as u32, width as u32, height as u32)
+            }
+            None => image,
+        };
+
+        if !self.keep_aspect_ratio {
+            return image.resize_exact(self.width, self.height, FilterType::Lanczos3);
+        }
+
+        let image = image
+            .resize(self.width, self.height, FilterType::Lanczos3)
+            .into_rgba32f();
+        let (left, top) = ((self.width - image.width()) / 2, (self.height - image.height()) / 2);
+        let (right, bottom) = (left + image.width() - 1, top + image.height() - 1);
+        DynamicImage::ImageRgba32F(Rgba32FImage::from_fn(self.width, self.height, |x, y| {
+            let mut pixel = *image.get_pixel(x.clamp(left, right) - left, y.clamp(top, bottom) - top);
+            if x < left || x > right || y < top || y > bottom {
+                pixel[3] = 0.0;
+            }
+            pixel
+        }))
+    }
+}
+
+/// Source images of one material in the ambientCG layout, e.g. `<name>_Color.png` and `<name>_NormalGL.png`.
+#[derive(Debug, Clone)]
+pub enum MaterialSource {
+    /// Archive, e.g. downloaded from ambientCG.
+    Zip(PathBuf),
+    /// Folder with extracted images, e.g. in a local import folder.
+    Dir(PathBuf),
+}
+
+impl MaterialSource {
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Zip(path) | Self::Dir(path) => path,
+        }
+    }
+
+    /// Material name, the archive name without extension or the folder name.
+    pub fn name(&self) -> String {
+        match self {
+            Self::Zip(path) => path.file_stem(),
+            Self::Dir(path) => path.file_name(),
+        }
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+    }
+
+    /// Download metadata is stored in a `.mtld` folder next to the source.
+    fn metadata_path(&self) -> PathBuf {
+        self.path()
+            .with_file_name(".mtld")
+            .join(self.name())
+            .with_extension("json")
+    }
+
+    /// Images of a folder source, sorted by name.
+    fn dir_image_paths(dir_path: &Path) -> Result<Vec<PathBuf>, ApplicationError> {
+        let mut image_paths = read_dir(dir_path)
+            .with_context(|| format!("reading {}", dir_path.display()))?
+            .filter_map(|f| f.ok())
+            .map(|f| f.path())
+            .filter(|f| f.is_file())
+            .collect::<Vec<_>>();
+        image_paths.sort();
+        Ok(image_paths)
+    }
+
+    fn open(&self) -> Result<SourceImages, ApplicationError> {
+        match self {
+            Self::Zip(zip_path) => File::open(zip_path)
+                .map_err(ApplicationError::from)
+                .and_then(|file| Ok(SourceImages::Zip(ZipArchive::new(BufReader::new(file))?)))
+                .with_context(|| format!("reading {}", zip_path.display())),
+            Self::Dir(dir_path) => Ok(SourceImages::Dir(Self::dir_image_paths(dir_path)?)),
+        }
+    }
+}
+
+/// Opened material source, images are addressed by their index in `names`.
+enum SourceImages {
+    Zip(ZipArchive<BufReader<File>>),
+    Dir(Vec<PathBuf>),
+}
+
+impl SourceImages {
+    fn names(&mut self) -> Result<Vec<String>, ApplicationError> {
+        match self {
+            Self::Zip(zip_archive) => (0..zip_archive.len())
+                .map(|image_index| Ok(zip_archive.by_index_raw(image_index)?.name().to_owned()))
+                .collect(),
+            Self::Dir(image_paths) => Ok(image_paths
+                .iter()
+                .map(|f| f.file_name().unwrap_or_default().to_string_lossy().into_owned())
+                .collect()),
+        }
+    }
+
+    fn load(&mut self, image_index: usize) -> Result<DynamicImage, ApplicationError> {
+        match self {
+            Self::Zip(zip_archive) => decompress_image(zip_archive, image_index),
+            Self::Dir(image_paths) => {
+                let image_path = &image_paths[image_index];
+                open(image_path).with_context(|| format!("reading {}", image_path.display()))
+            }
+        }
+    }
+}
+
+/// Lists archives in the download cache and material folders and archives in local source folders. When several
+/// sources have the same name, the first one is used.
+pub fn collect_sources(
     download_cache_path: &Path,
+    source_paths: &[PathBuf],
+) -> Result<Vec<MaterialSource>, ApplicationError> {
+    let mut sources = Vec::<MaterialSource>::new();
+    for (folder_path, local) in Some((download_cache_path, false))
+        .into_iter()
+        .chain(source_paths.iter().map(|f| (f.as_path(), true)))
+    {
+        // The download cache doesn't exist until something is downloaded.
+        if !local && !folder_path.exists() {
+            continue;
+        }
+
+        let mut paths = read_dir(folder_path)
+            .with_context(|| format!("reading {}", folder_path.display()))?
+            .filter_map(|f| f.ok())
+            .map(|f| f.path())
+            .filter(|f| !f.file_name().unwrap_or_default().to_string_lossy().starts_with('.'))
+            .collect::<Vec<_>>();
+        paths.sort();
+
+        for path in paths {
+            let source = if path.is_file() && path.extension().is_some_and(|e| e == "zip") {
+                MaterialSource::Zip(path)
+            } else if local && path.is_dir() {
+                MaterialSource::Dir(path)
+            } else {
+                continue;
+            };
+
+            match sources.iter().find(|f| f.name() == source.name()) {
+                Some(existing_source) => log::warn!(
+                    "{} is ignored, {} has the same name",
+                    source.path().display(),
+                    existing_source.path().display()
+                ),
+                None => sources.push(source),
+            }
+        }
+    }
+    Ok(sources)
+}
+
+pub fn pack(
+    sources: &[MaterialSource],
+    force_pack: bool,
+    pack_single_threaded: bool,
+    pack_cache_path: &Path,
+    pack_settings: &PackSettings,
+    failures: &FailureLog,
+) -> Result<(), ApplicationError> {
+    create_dir_all(pack_cache_path).with_context(|| format!("creating {}", pack_cache_path.display()))?;
+
+    let manifest = BuildManifest::load(pack_cache_path)?;
+    let result = pack_all(
+        &manifest,
+        failures,
+        sources,
+        force_pack,
+        pack_single_threaded,
+        pack_cache_path,
+        pack_settings,
+    );
+    manifest.save()?;
+    result
+}
+
+fn pack_all(
+    manifest: &BuildManifest,
+    failures: &FailureLog,
+    sources: &[MaterialSource],
     force_pack: bool,
     pack_single_threaded: bool,
     pack_cache_path: &Path,
-    pack_normal_map_type: NormalMapType,
-    pack_target_width: u32,
-    pack_target_height: u32,
+    pack_settings: &PackSettings,
 ) -> Result<(), ApplicationError> {
-    create_dir_all(pack_cache_path)?;
+    log::info!("PACK {} assets", sources.len());
+    let progress = StageProgress::new("pack", sources.len() as u64);
 
-    let directory_contents = read_dir(download_cache_path)?
-        .filter_map(|f| f.ok())
-        .map(|f| f.path())
-        .filter(|f| f.is_file());
+    let pack_source = |source: &MaterialSource| {
+        let source_name = source.name();
+        let result = failures.handle(
+            "pack",
+            &source_name,
+            pack_material(manifest, source, pack_cache_path, pack_settings, force_pack).and_then(|_| {
+                for region in pack_settings.atlas_regions.get(&source_name).into_iter().flatten() {
+                    pack_atlas_region(manifest, source, region, pack_cache_path, pack_settings, force_pack)?;
+                }
+                Ok(())
+            }),
+        );
+        progress.inc(&source_name);
+        result
+    };
 
     if pack_single_threaded {
-        let temp_file_path = pack_cache_path.join("mtldpack.tmp");
-        for zip_path in directory_contents {
-            pack_single_image(
-                &temp_file_path,
-                &zip_path,
-                force_pack,
-                pack_cache_path,
-                pack_normal_map_type,
-                pack_target_width,
-                pack_target_height,
-            )?;
+        for source in sources {
+            pack_source(source)?;
         }
     } else {
-        directory_contents
-            .collect::<Vec<PathBuf>>()
+        sources
             .par_iter()
-            .map(|zip_path| {
-                let temp_file_path =
-                    pack_cache_path.join(format!("mtldpack{}.tmp", rayon::current_thread_index().unwrap_or(0)));
-                pack_single_image(
-                    &temp_file_path,
-                    zip_path,
-                    force_pack,
-                    pack_cache_path,
-                    pack_normal_map_type,
-                    pack_target_width,
-                    pack_target_height,
-                )
-            })
+            .map(pack_source)
             .collect::<Result<Vec<_>, ApplicationError>>()?;
     }
+
+    progress.finish();
     Ok(())
 }
 
-fn pack_single_image(
-    temp_file_path: &Path,
-    zip_path: &Path,
+/// Name of the packed material of a source, or of one of its atlas regions.
+pub fn packed_name(source: &MaterialSource, region: Option<&AtlasRegion>) -> String {
+    match region {
+        Some(region) => format!("{}_{}", source.name(), region.name),
+        None => source.name(),
+    }
+}
+
+/// Returns true when the sources of a packed material, or of one of its atlas regions, changed since it was packed. The
+/// material is checked with the settings it was packed with, as recorded in the build manifest, materials packed
+/// before settings were recorded are never outdated.
+pub fn is_outdated(
+    manifest: &BuildManifest,
+    source: &MaterialSource,
+    region: Option<&AtlasRegion>,
+    pack_cache_path: &Path,
+) -> Result<bool, ApplicationError> {
+    let material_json_path = pack_cache_path.join(packed_name(source, region)).join("Material.json");
+    let Some(pack_settings) = manifest
+        .recorded_settings(&material_json_path)
+        .and_then(|f| PackSettings::from_json(&f))
+    else {
+        return Ok(false);
+    };
+    Ok(manifest.is_recorded(&material_json_path)
+        && !manifest.is_up_to_date(
+            &material_json_path,
+            &input_hash(manifest, source, region, &pack_settings)?,
+        ))
+}
+
+fn input_hash(
+    manifest: &BuildManifest,
+    source: &MaterialSource,
+    region: Option<&AtlasRegion>,
+    pack_settings: &PackSettings,
+) -> Result<String, ApplicationError> {
+    let mut input_hasher = manifest.hasher("pack", OUTPUT_VERSION);
+    match source {
+        MaterialSource::Zip(zip_path) => input_hasher = input_hasher.file(zip_path)?,
+        MaterialSource::Dir(dir_path) => {
+            for image_path in MaterialSource::dir_image_paths(dir_path)? {
+                input_hasher = input_hasher
+                    .setting("image", image_path.file_name().unwrap_or_default().to_string_lossy())
+                    .file(&image_path)?;
+            }
+        }
+    }
+
+    input_hasher = input_hasher
+        .file(&source.metadata_path())?
+        .setting("normal_map_type", format!("{:?}", pack_settings.normal_map_type))
+        .setting("target_width", pack_settings.target_width)
+        .setting("target_height", pack_settings.target_height)
+        .setting("material_pack_layout", format!("{:?}", MATERIAL_PACK_LAYOUT))
+        .setting("surface_pack_layout", format!("{:?}", SURFACE_PACK_LAYOUT));
+    if let Some(region) = region {
+        input_hasher = input_hasher.setting("atlas_region", format!("{:?}", region));
+    }
+    if pack_settings.dilate_colors {
+        input_hasher = input_hasher.setting("dilate_colors", pack_settings.dilate_colors);
+    }
+    if pack_settings.generate_normals {
+        input_hasher = input_hasher
+            .setting("normal_strength", pack_settings.normal_strength)
+            .setting("normal_kernel", format!("{:?}", pack_settings.normal_kernel));
+    }
+    if pack_settings.bake_ao {
+        input_hasher = input_hasher
+            .setting("ao_strength", pack_settings.ao_strength)
+            .setting("ao_radius", pack_settings.ao_radius);
+    }
+    Ok(input_hasher.finish())
+}
+
+/// Packs one material source into `<pack_cache_path>/<name>`, skipping packed files that are up to date.
+pub fn pack_material(
+    manifest: &BuildManifest,
+    source: &MaterialSource,
+    pack_cache_path: &Path,
+    pack_settings: &PackSettings,
+    force_pack: bool,
+) -> Result<MaterialOutputs, ApplicationError> {
+    pack_images(manifest, source, None, pack_cache_path, pack_settings, force_pack)
+}
+
+/// Packs a region of an atlas source into `<pack_cache_path>/<atlas>_<region>`, like a decal cut out of the atlas.
+pub fn pack_atlas_region(
+    manifest: &BuildManifest,
+    source: &MaterialSource,
+    region: &AtlasRegion,
+    pack_cache_path: &Path,
+    pack_settings: &PackSettings,
     force_pack: bool,
+) -> Result<MaterialOutputs, ApplicationError> {
+    pack_images(
+        manifest,
+        source,
+        Some(region),
+        pack_cache_path,
+        pack_settings,
+        force_pack,
+    )
+}
+
+fn pack_images(
+    manifest: &BuildManifest,
+    source: &MaterialSource,
+    region: Option<&AtlasRegion>,
     pack_cache_path: &Path,
-    pack_normal_map_type: NormalMapType,
-    pack_target_width: u32,
-    pack_target_height: u32,
-) -> Result<(), ApplicationError> {
-    if let Some(zip_name) = zip_path.file_stem().and_then(|p| p.to_str()) {
-        println!("PACK {:?}", zip_name);
-
-        let mut zip_archive = ZipArchive::new(BufReader::new(File::open(zip_path)?))?;
-        let mut albedo_image = None;
-        let mut opacity_image = None;
-        let mut normal_image = None;
-        let mut metalness_image = None;
-        let mut roughness_image = None;
-        let mut ao_image = None;
-        let mut displacement_image = None;
-
-        for image_index in 0..zip_archive.len() {
-            let file = zip_archive.by_index_raw(image_index)?;
-            if let Some(image_type) = file.name().split('.').next() {
-                if image_type.ends_with("_Color") {
-                    albedo_image = Some(image_index);
-                } else if image_type.ends_with("_Opacity") {
-                    opacity_image = Some(image_index);
-                } else if image_type.ends_with("_Metalness") {
-                    metalness_image = Some(image_index);
-                } else if image_type.ends_with("_Roughness") {
-                    roughness_image = Some(image_index);
-                } else if image_type.ends_with("_AmbientOcclusion") {
-                    ao_image = Some(image_index);
-                } else if image_type.ends_with("_Displacement") {
-                    displacement_image = Some(image_index);
-                } else {
-                    match pack_normal_map_type {
-                        NormalMapType::OpenGL => {
-                            if image_type.ends_with("_NormalGL") {
-                                normal_image = Some(image_index);
-                            }
+    pack_settings: &PackSettings,
+    force_pack: bool,
+) -> Result<MaterialOutputs, ApplicationError> {
+    let pack_normal_map_type = pack_settings.normal_map_type;
+    let pack_target_width = pack_settings.target_width;
+    let pack_target_height = pack_settings.target_height;
+
+    let material_name = packed_name(source, region);
+    log::debug!("PACK {:?}", material_name);
+
+    let download_json_path = source.metadata_path();
+    let input_hash = input_hash(manifest, source, region, pack_settings)?;
+
+    let download_json: Option<serde_json::Value> = if download_json_path.exists() {
+        Some(
+            serde_json::from_str(
+                &read_to_string(&download_json_path)
+                    .with_context(|| format!("reading {}", download_json_path.display()))?,
+            )
+            .with_context(|| format!("parsing {}", download_json_path.display()))?,
+        )
+    } else {
+        None
+    };
+    let material_type = download_json
+        .as_ref()
+        .and_then(|f| f.as_object())
+        .and_then(|f| f.get("type"))
+        .and_then(|f| f.as_str());
+
+    let fit = ImageFit {
+        region: region.cloned(),
+        keep_aspect_ratio: region.is_some() || matches!(material_type, Some("Decal" | "Atlas")),
+        width: pack_target_width,
+        height: pack_target_height,
+    };
+
+    let mut source_images = source.open()?;
+    let mut albedo_image = None;
+    let mut opacity_image = None;
+    let mut normal_image = None;
+    let mut metalness_image = None;
+    let mut roughness_image = None;
+    let mut ao_image = None;
+    let mut displacement_image = None;
+    let mut diffuse_image = None;
+    let mut specular_image = None;
+    let mut glossiness_image = None;
+    let mut emission_image = None;
+    let mut subsurface_image = None;
+    let mut translucency_image = None;
+
+    for (image_index, image_name) in source_images.names()?.iter().enumerate() {
+        if let Some(image_type) = image_name.split('.').next() {
+            if image_type.ends_with("_Color") {
+                albedo_image = Some(image_index);
+            } else if image_type.ends_with("_Opacity") {
+                opacity_image = Some(image_index);
+            } else if image_type.ends_with("_Metalness") {
+                metalness_image = Some(image_index);
+            } else if image_type.ends_with("_Roughness") {
+                roughness_image = Some(image_index);
+            } else if image_type.ends_with("_AmbientOcclusion") {
+                ao_image = Some(image_index);
+            } else if image_type.ends_with("_Displacement") {
+                displacement_image = Some(image_index);
+            } else if image_type.ends_with("_Diffuse") {
+                diffuse_image = Some(image_index);
+            } else if image_type.ends_with("_Specular") {
+                specular_image = Some(image_index);
+            } else if image_type.ends_with("_Glossiness") {
+                glossiness_image = Some(image_index);
+            } else if image_type.ends_with("_Emission") {
+                emission_image = Some(image_index);
+            } else if image_type.ends_with("_Subsurface") {
+                subsurface_image = Some(image_index);
+            } else if image_type.ends_with("_Translucency") {
+                translucency_image = Some(image_index);
+            } else {
+                match pack_normal_map_type {
+                    NormalMapType::OpenGL => {
+                        if image_type.ends_with("_NormalGL") {
+                            normal_image = Some(image_index);
                         }
+                    }
 
-                        NormalMapType::Direct3D => {
-                            if image_type.ends_with("_NormalDX") {
-                                normal_image = Some(image_index);
-                            }
+                    NormalMapType::Direct3D => {
+                        if image_type.ends_with("_NormalDX") {
+                            normal_image = Some(image_index);
                         }
                     }
                 }
             }
         }
+    }
 
-        let target_path = pack_cache_path.join(zip_name);
-        create_dir_all(&target_path)?;
-
-        let material_json_path = target_path.join("Material.json");
-        if force_pack || !material_json_path.exists() {
-            if let Some(zip_directory) = zip_path.parent() {
-                let download_json_path = zip_directory.join(".mtld").join(zip_name).with_extension("json");
-                let mut material_category = None;
-                let mut material_type = None;
-                let mut material_creation_method = None;
-
-                let download_json: serde_json::Value;
-                if download_json_path.exists() {
-                    download_json = serde_json::from_str(&read_to_string(&download_json_path)?)?;
-
-                    material_category = download_json
-                        .as_object()
-                        .and_then(|f| f.get("category"))
-                        .and_then(|f| f.as_str());
-                    material_type = download_json
-                        .as_object()
-                        .and_then(|f| f.get("type"))
-                        .and_then(|f| f.as_str());
-                    material_creation_method = download_json
-                        .as_object()
-                        .and_then(|f| f.get("method"))
-                        .and_then(|f| f.as_str());
-                }
+    let generate_normal = pack_settings.generate_normals && normal_image.is_none() && displacement_image.is_some();
+    let bake_ao = pack_settings.bake_ao && ao_image.is_none() && displacement_image.is_some();
+    // Spec/gloss sources, e.g. local imports from older engines, are converted to metal/rough.
+    let spec_gloss_source = albedo_image.is_none() && diffuse_image.is_some();
+    let material_pack_data = [
+        metalness_image.is_some() || (spec_gloss_source && specular_image.is_some()),
+        ao_image.is_some() || bake_ao,
+        displacement_image.is_some(),
+        roughness_image.is_some() || (spec_gloss_source && glossiness_image.is_some()),
+    ];
+    // `_Specular` is the specular color of spec/gloss sources and the specular level of metal/rough sources.
+    let specular_level_image = specular_image.filter(|_| !spec_gloss_source);
+    // Padded images get an alpha channel even without an opacity map, so the padding is transparent.
+    let has_alpha = opacity_image.is_some() || fit.keep_aspect_ratio;
+    let surface_pack_images = [subsurface_image, translucency_image, specular_level_image];
 
-                write(
-                    &material_json_path,
-                    format!(
-                        concat!(
-                            "{{\n",
-                            " \"name\": {:?},\n",
-                            " \"category\": {:?},\n",
-                            " \"type\": {:?},\n",
-                            " \"method\": {:?},\n",
-                            " \"albedo\": {},\n",
-                            " \"opacity\": {},\n",
-                            " \"normal\": {},\n",
-                            " \"metalness\": {},\n",
-                            " \"roughness\": {},\n",
-                            " \"ao\": {},\n",
-                            " \"displacement\": {}\n",
-                            "}}",
-                        ),
-                        zip_name,
-                        material_category.unwrap_or("null"),
-                        material_type.unwrap_or("null"),
-                        material_creation_method.unwrap_or("null"),
-                        albedo_image.is_some(),
-                        opacity_image.is_some(),
-                        normal_image.is_some(),
-                        metalness_image.is_some(),
-                        roughness_image.is_some(),
-                        ao_image.is_some(),
-                        displacement_image.is_some(),
-                    ),
-                )?;
-            }
+    let target_path = pack_cache_path.join(&material_name);
+    create_dir_all(&target_path).with_context(|| format!("creating {}", target_path.display()))?;
+    let temp_file_path = target_path.join("mtldpack.tmp");
+    let mut outputs = MaterialOutputs::new(&material_name);
+
+    let material_json_path = target_path.join("Material.json");
+    let material_json_outdated = force_pack || !manifest.is_up_to_date(&material_json_path, &input_hash);
+    if material_json_outdated {
+        let metadata = |name: &str| {
+            download_json
+                .as_ref()
+                .and_then(|f| f.as_object())
+                .and_then(|f| f.get(name))
+        };
+        let material_category = metadata("category").and_then(|f| f.as_str());
+        let material_creation_method = metadata("method").and_then(|f| f.as_str());
+        let material_tags = metadata("tags").and_then(|f| f.as_array());
+        let material_source = metadata("source").and_then(|f| f.as_str());
+        let material_url = metadata("url").and_then(|f| f.as_str());
+        let material_license = metadata("license").and_then(|f| f.as_str());
+
+        write(
+            &material_json_path,
+            format!(
+                concat!(
+                    "{{\n",
+                    " \"name\": {},\n",
+                    " \"category\": {},\n",
+                    " \"type\": {},\n",
+                    " \"method\": {},\n",
+                    " \"atlas\": {},\n",
+                    " \"region\": {},\n",
+                    " \"tags\": {},\n",
+                    " \"source\": {},\n",
+                    " \"url\": {},\n",
+                    " \"license\": {},\n",
+                    " \"albedo\": {},\n",
+                    " \"opacity\": {},\n",
+                    " \"alpha\": {},\n",
+                    " \"normal\": {},\n",
+                    " \"normal_generated\": {},\n",
+                    " \"metalness\": {},\n",
+                    " \"roughness\": {},\n",
+                    " \"ao\": {},\n",
+                    " \"ao_generated\": {},\n",
+                    " \"displacement\": {},\n",
+                    " \"emission\": {},\n",
+                    " \"subsurface\": {},\n",
+                    " \"translucency\": {},\n",
+                    " \"specular\": {},\n",
+                    " \"source_workflow\": {:?},\n",
+                    " \"channels\": {}\n",
+                    "}}",
+                ),
+                serde_json::json!(material_name),
+                serde_json::json!(material_category),
+                serde_json::json!(material_type),
+                serde_json::json!(material_creation_method),
+                serde_json::json!(region.map(|_| source.name())),
+                serde_json::json!(region.map(AtlasRegion::to_json)),
+                serde_json::json!(material_tags.cloned().unwrap_or_default()),
+                serde_json::json!(material_source),
+                serde_json::json!(material_url),
+                serde_json::json!(material_license),
+                albedo_image.is_some() || spec_gloss_source,
+                opacity_image.is_some(),
+                has_alpha,
+                normal_image.is_some() || generate_normal,
+                generate_normal,
+                material_pack_data[0],
+                material_pack_data[3],
+                ao_image.is_some() || bake_ao,
+                bake_ao,
+                displacement_image.is_some(),
+                emission_image.is_some(),
+                subsurface_image.is_some(),
+                translucency_image.is_some(),
+                specular_level_image.is_some(),
+                if spec_gloss_source { "spec_gloss" } else { "metal_rough" },
+                serde_json::Value::Object(
+                    izip!(&MATERIAL_PACK_LAYOUT, ["R", "G", "B", "A"], material_pack_data)
+                        .map(|(channel, channel_name, has_data)| ("MODR", channel, channel_name, has_data))
+                        .chain(
+                            izip!(&SURFACE_PACK_LAYOUT, ["R", "G", "B"], surface_pack_images)
+                                .map(|(channel, channel_name, image)| ("STS", channel, channel_name, image.is_some()))
+                        )
+                        .map(|(texture, channel, channel_name, has_data)| {
+                            (
+                                channel.map.to_owned(),
+                                serde_json::json!({
+                                    "texture": texture,
+                                    "channel": channel_name,
+                                    "data": has_data,
+                                    "default": channel.default_value,
+                                }),
+                            )
+                        })
+                        .collect()
+                ),
+            ),
+        )?;
+        manifest.record(&material_json_path, &input_hash);
+        manifest.record_settings(&material_json_path, pack_settings.to_json());
+    }
+    outputs.push(material_json_path, material_json_outdated);
+
+    let albedo_image_path = target_path.join(format!("{}_A.png", material_name));
+    let albedo_outdated = force_pack || !manifest.is_up_to_date(&albedo_image_path, &input_hash);
+    let material_pack_image_path = target_path.join(format!("{}_MODR.png", material_name));
+    let material_pack_outdated = force_pack || !manifest.is_up_to_date(&material_pack_image_path, &input_hash);
+
+    // Spec/gloss sources are converted once for both the albedo and the material pack image.
+    let mut metal_rough = match diffuse_image.filter(|_| spec_gloss_source) {
+        Some(diffuse_image) if albedo_outdated || material_pack_outdated => {
+            let mut load_image = |image_index: Option<usize>| {
+                image_index
+                    .map(|image_index| source_images.load(image_index))
+                    .transpose()
+                    .map(|f| f.map(|f| fit.apply(f)))
+            };
+            let diffuse_image = load_image(Some(diffuse_image))?.map(|f| f.into_rgba8());
+            let specular_image = load_image(specular_image)?.map(|f| f.into_rgb8());
+            let glossiness_image = load_image(glossiness_image)?.map(|f| f.into_luma8());
+            diffuse_image.map(|diffuse_image| {
+                workflow::metal_rough_from_spec_gloss(
+                    &diffuse_image,
+                    specular_image.as_ref(),
+                    glossiness_image.as_ref(),
+                )
+            })
         }
+        _ => None,
+    };
 
-        let albedo_image_path = target_path.join(format!("{}_A.png", zip_name));
-        if force_pack || !albedo_image_path.exists() {
-            if let Some(albedo_image) = albedo_image {
-                let albedo_image = decompress_image(&mut zip_archive, albedo_image)?
-                    .resize_exact(pack_target_width, pack_target_height, FilterType::Lanczos3)
-                    .into_rgb8();
-                if let Some(opacity_image) = opacity_image {
-                    let opacity_image = decompress_image(&mut zip_archive, opacity_image)?
-                        .resize_exact(pack_target_width, pack_target_height, FilterType::Lanczos3)
-                        .into_luma8();
-
-                    let mut target_albedo_image = RgbaImage::new(pack_target_width, pack_target_height);
-
-                    for (target, color, opacity) in izip!(
-                        target_albedo_image.pixels_mut(),
-                        albedo_image.pixels(),
-                        opacity_image.pixels()
-                    ) {
-                        target[0] = color[0];
-                        target[1] = color[1];
-                        target[2] = color[2];
-                        target[3] = opacity[0];
-                    }
+    if albedo_image.is_some() || spec_gloss_source {
+        let albedo_image = match (albedo_image, &metal_rough) {
+            (Some(albedo_image), _) if albedo_outdated => {
+                Some(fit.apply(source_images.load(albedo_image)?).into_rgba8())
+            }
+            (None, Some(metal_rough)) if albedo_outdated => Some(metal_rough.albedo.clone()),
+            _ => None,
+        };
+        if let Some(mut albedo_image) = albedo_image {
+            if let Some(opacity_image) = opacity_image {
+                let opacity_image = fit.apply(source_images.load(opacity_image)?).into_luma8();
 
-                    target_albedo_image.save_with_format(&temp_file_path, ImageFormat::Png)?;
-                } else {
-                    albedo_image.save_with_format(&temp_file_path, ImageFormat::Png)?;
+                // The albedo alpha is zero in the padding of decals and one everywhere else.
+                for (target, opacity) in izip!(albedo_image.pixels_mut(), opacity_image.pixels()) {
+                    target[3] = (target[3] as u32 * opacity[0] as u32 / 255) as u8;
                 }
-
-                rename(temp_file_path, &albedo_image_path)?;
             }
-        }
 
-        let normal_image_path = target_path.join(format!("{}_N.png", zip_name));
-        if force_pack || !normal_image_path.exists() {
-            if let Some(normal_image) = normal_image {
-                let normal_image = decompress_image(&mut zip_archive, normal_image)?
-                    .resize_exact(pack_target_width, pack_target_height, FilterType::Lanczos3)
-                    .into_rgb8();
-                normal_image.save_with_format(&temp_file_path, ImageFormat::Png)?;
-                rename(temp_file_path, &normal_image_path)?;
+            if has_alpha {
+                if pack_settings.dilate_colors {
+                    dilate::dilate_colors(&mut albedo_image);
+                }
+                albedo_image
+                    .save_with_format(&temp_file_path, ImageFormat::Png)
+                    .with_context(|| format!("writing {}", temp_file_path.display()))?;
+            } else {
+                DynamicImage::ImageRgba8(albedo_image)
+                    .into_rgb8()
+                    .save_with_format(&temp_file_path, ImageFormat::Png)
+                    .with_context(|| format!("writing {}", temp_file_path.display()))?;
             }
+
+            rename(&temp_file_path, &albedo_image_path)
+                .with_context(|| format!("writing {}", albedo_image_path.display()))?;
+            manifest.record(&albedo_image_path, &input_hash);
         }
+        outputs.push(albedo_image_path.clone(), albedo_outdated);
+    }
 
-        if roughness_image.is_some() || metalness_image.is_some() || displacement_image.is_some() || ao_image.is_some()
-        {
-            let material_pack_image_path = target_path.join(format!("{}_MODR.png", zip_name));
-            if force_pack || !material_pack_image_path.exists() {
-                let mut material_pack_image = RgbaImage::new(pack_target_width, pack_target_height);
+    let normal_image_path = target_path.join(format!("{}_N.png", material_name));
+    let normal_outdated = force_pack || !manifest.is_up_to_date(&normal_image_path, &input_hash);
+    if let Some(normal_image) = normal_image.or(displacement_image.filter(|_| generate_normal)) {
+        if normal_outdated {
+            let normal_image = fit.apply(source_images.load(normal_image)?);
+            let normal_image = if generate_normal {
+                bake::normal_from_height(
+                    &HeightMap::new(&normal_image),
+                    pack_settings.normal_strength,
+                    pack_settings.normal_kernel,
+                    pack_normal_map_type,
+                )
+            } else {
+                normal_image.into_rgb8()
+            };
+            normal_image
+                .save_with_format(&temp_file_path, ImageFormat::Png)
+                .with_context(|| format!("writing {}", temp_file_path.display()))?;
+            rename(&temp_file_path, &normal_image_path)
+                .with_context(|| format!("writing {}", normal_image_path.display()))?;
+            manifest.record(&normal_image_path, &input_hash);
+        }
+        outputs.push(normal_image_path, normal_outdated);
+    }
 
-                if let Some(metalness_image) = metalness_image {
-                    let metalness_image = decompress_image(&mut zip_archive, metalness_image)?
-                        .resize_exact(pack_target_width, pack_target_height, FilterType::Lanczos3)
-                        .into_luma8();
+    if material_pack_data.iter().any(|has_data| *has_data) {
+        if material_pack_outdated {
+            let mut load_channel = |image_index: Option<usize>| {
+                image_index
+                    .map(|image_index| source_images.load(image_index))
+                    .transpose()
+                    .map(|f| f.map(|f| fit.apply(f)))
+            };
 
-                    for (target, metalness) in izip!(material_pack_image.pixels_mut(), metalness_image.pixels()) {
-                        target[0] = metalness[0];
-                    }
-                }
+            let metalness_image = load_channel(metalness_image)?;
+            let displacement_image = load_channel(displacement_image)?;
+            let ao_image = match (load_channel(ao_image)?, &displacement_image) {
+                (Some(ao_image), _) => Some(ao_image.into_luma8()),
+                (None, Some(displacement_image)) if bake_ao => Some(bake::ao_from_height(
+                    &HeightMap::new(displacement_image),
+                    pack_settings.ao_strength,
+                    pack_settings.ao_radius,
+                )),
+                _ => None,
+            };
+            let roughness_image = load_channel(roughness_image)?;
 
-                if let Some(ao_image) = ao_image {
-                    let ao_image = decompress_image(&mut zip_archive, ao_image)?
-                        .resize_exact(pack_target_width, pack_target_height, FilterType::Lanczos3)
-                        .into_luma8();
+            let channel_images = [
+                metalness_image
+                    .map(|f| f.into_luma8())
+                    .or_else(|| metal_rough.as_mut().and_then(|f| f.metalness.take())),
+                ao_image,
+                displacement_image.map(|f| f.into_luma8()),
+                roughness_image
+                    .map(|f| f.into_luma8())
+                    .or_else(|| metal_rough.as_mut().and_then(|f| f.roughness.take())),
+            ];
 
-                    for (target, ao) in izip!(material_pack_image.pixels_mut(), ao_image.pixels()) {
-                        target[1] = ao[0];
+            let mut material_pack_image = RgbaImage::new(pack_target_width, pack_target_height);
+            for (channel_index, (channel, channel_image)) in
+                MATERIAL_PACK_LAYOUT.iter().zip(&channel_images).enumerate()
+            {
+                match channel_image {
+                    Some(channel_image) => {
+                        for (target, value) in izip!(material_pack_image.pixels_mut(), channel_image.pixels()) {
+                            target[channel_index] = value[0];
+                        }
+                    }
+                    None => {
+                        for target in material_pack_image.pixels_mut() {
+                            target[channel_index] = channel.default_byte();
+                        }
                     }
                 }
+            }
+
+            material_pack_image
+                .save_with_format(&temp_file_path, ImageFormat::Png)
+                .with_context(|| format!("writing {}", temp_file_path.display()))?;
+            rename(&temp_file_path, &material_pack_image_path)
+                .with_context(|| format!("writing {}", material_pack_image_path.display()))?;
+            manifest.record(&material_pack_image_path, &input_hash);
+        }
+        outputs.push(material_pack_image_path.clone(), material_pack_outdated);
+    }
 
-                if let Some(displacement_image) = displacement_image {
-                    let displacement_image = decompress_image(&mut zip_archive, displacement_image)?
-                        .resize_exact(pack_target_width, pack_target_height, FilterType::Lanczos3)
-                        .into_luma8();
+    if let Some(emission_image) = emission_image {
+        let emission_image_path = target_path.join(format!("{}_E.png", material_name));
+        let emission_outdated = force_pack || !manifest.is_up_to_date(&emission_image_path, &input_hash);
+        if emission_outdated {
+            fit.apply(source_images.load(emission_image)?)
+                .into_rgb8()
+                .save_with_format(&temp_file_path, ImageFormat::Png)
+                .with_context(|| format!("writing {}", temp_file_path.display()))?;
+            rename(&temp_file_path, &emission_image_path)
+                .with_context(|| format!("writing {}", emission_image_path.display()))?;
+            manifest.record(&emission_image_path, &input_hash);
+        }
+        outputs.push(emission_image_path, emission_outdated);
+    }
 
-                    for (target, displacement) in izip!(material_pack_image.pixels_mut(), displacement_image.pixels()) {
-                        target[2] = displacement[0];
+    if surface_pack_images.iter().any(|f| f.is_some()) {
+        let surface_pack_image_path = target_path.join(format!("{}_STS.png", material_name));
+        let surface_pack_outdated = force_pack || !manifest.is_up_to_date(&surface_pack_image_path, &input_hash);
+        if surface_pack_outdated {
+            let mut surface_pack_image = RgbImage::new(pack_target_width, pack_target_height);
+            for (channel_index, (channel, channel_image)) in
+                SURFACE_PACK_LAYOUT.iter().zip(surface_pack_images).enumerate()
+            {
+                match channel_image {
+                    Some(channel_image) => {
+                        let channel_image = fit.apply(source_images.load(channel_image)?).into_luma8();
+                        for (target, value) in izip!(surface_pack_image.pixels_mut(), channel_image.pixels()) {
+                            target[channel_index] = value[0];
+                        }
+                    }
+                    None => {
+                        for target in surface_pack_image.pixels_mut() {
+                            target[channel_index] = channel.default_byte();
+                        }
                     }
                 }
+            }
 
-                if let Some(roughness_image) = roughness_image {
-                    let roughness_image = decompress_image(&mut zip_archive, roughness_image)?
-                        .resize_exact(pack_target_width, pack_target_height, FilterType::Lanczos3)
-                        .into_luma8();
+            surface_pack_image
+                .save_with_format(&temp_file_path, ImageFormat::Png)
+                .with_context(|| format!("writing {}", temp_file_path.display()))?;
+            rename(&temp_file_path, &surface_pack_image_path)
+                .with_context(|| format!("writing {}", surface_pack_image_path.display()))?;
+            manifest.record(&surface_pack_image_path, &input_hash);
+        }
+        outputs.push(surface_pack_image_path, surface_pack_outdated);
+    }
 
-                    for (target, roughness) in izip!(material_pack_image.pixels_mut(), roughness_image.pixels()) {
-                        target[3] = roughness[0];
-                    }
-                }
+    if pack_settings.spec_gloss && (albedo_image.is_some() || spec_gloss_source) {
+        let diffuse_image_path = target_path.join(format!("{}_D.png", material_name));
+        let specular_image_path = target_path.join(format!("{}_SG.png", material_name));
+        let spec_gloss_outdated = force_pack
+            || !manifest.is_up_to_date(&diffuse_image_path, &input_hash)
+            || !manifest.is_up_to_date(&specular_image_path, &input_hash);
+        if spec_gloss_outdated {
+            let albedo_image = load_png(&albedo_image_path)?.into_rgba8();
+            let material_pack_image = if material_pack_data.iter().any(|has_data| *has_data) {
+                load_png(&material_pack_image_path)?.into_rgba8()
+            } else {
+                RgbaImage::from_pixel(
+                    albedo_image.width(),
+                    albedo_image.height(),
+                    Rgba(MATERIAL_PACK_LAYOUT.each_ref().map(|f| f.default_byte())),
+                )
+            };
+            let spec_gloss = workflow::spec_gloss_from_metal_rough(&albedo_image, &material_pack_image);
 
-                material_pack_image.save_with_format(temp_file_path, ImageFormat::Png)?;
-                rename(temp_file_path, &material_pack_image_path)?;
+            // The diffuse image keeps the alpha channel only when the albedo has one, like `_A.png`.
+            let diffuse_image = if has_alpha {
+                DynamicImage::ImageRgba8(spec_gloss.diffuse)
+            } else {
+                DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(spec_gloss.diffuse).into_rgb8())
+            };
+            for (image, image_path) in [
+                (diffuse_image, &diffuse_image_path),
+                (
+                    DynamicImage::ImageRgba8(spec_gloss.specular_glossiness),
+                    &specular_image_path,
+                ),
+            ] {
+                image
+                    .save_with_format(&temp_file_path, ImageFormat::Png)
+                    .with_context(|| format!("writing {}", temp_file_path.display()))?;
+                rename(&temp_file_path, image_path).with_context(|| format!("writing {}", image_path.display()))?;
+                manifest.record(image_path, &input_hash);
             }
         }
+        outputs.push(diffuse_image_path, spec_gloss_outdated);
+        outputs.push(specular_image_path, spec_gloss_outdated);
     }
 
-    Ok(())
+    Ok(outputs)
 }
 
 fn decompress_image<R: Read + Seek>(
@@ -304,9 +1059,18 @@ fn decompress_image<R: Read + Seek>(
     image_index: usize,
 ) -> Result<DynamicImage, ApplicationError> {
     let mut image_file = zip_archive.by_index(image_index)?;
+    let image_name = image_file.name().to_owned();
 
     let mut image_data = Vec::with_capacity(image_file.size() as _);
-    copy(&mut image_file, &mut image_data)?;
+    copy(&mut image_file, &mut image_data).with_context(|| format!("extracting {}", image_name))?;
+
+    load_from_memory(&image_data).with_context(|| format!("decoding {}", image_name))
+}
 
-    Ok(load_from_memory(&image_data)?)
+/// Loads a packed image from the pack cache.
+pub fn load_png(image_path: &Path) -> Result<DynamicImage, ApplicationError> {
+    File::open(image_path)
+        .map_err(ApplicationError::from)
+        .and_then(|file| Ok(load(BufReader::new(file), ImageFormat::Png)?))
+        .with_context(|| format!("reading {}", image_path.display()))
 }
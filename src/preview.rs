@@ -0,0 +1,433 @@
+// Copyright (c) 2021 Kyrylo Bazhenov
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use image::*;
+use rayon::iter::*;
+use std::collections::BTreeSet;
+use std::f32::consts::PI;
+use std::fs::*;
+use std::path::*;
+use std::str::FromStr;
+
+use crate::application_error::*;
+use crate::color::{linear_to_srgb, srgb_to_linear};
+use crate::failures::*;
+use crate::logging::*;
+use crate::manifest::*;
+use crate::material::*;
+use crate::pack::{self, NormalMapType};
+
+/// Output version of rendered previews, bump when the renderer or lighting changes.
+const OUTPUT_VERSION: u32 = 1;
+
+/// Subpixels per axis, previews are rendered at this many times their size and averaged down.
+const PREVIEW_SUPERSAMPLING: u32 = 2;
+
+/// Texture repeats across the preview shape, so tiling seams are visible.
+const PREVIEW_TILING: f32 = 2.0;
+
+/// Key light direction and color, plus sky and ground colors of the hemisphere ambient light, all in linear space.
+const KEY_LIGHT_DIRECTION: [f32; 3] = [-0.45, 0.6, 0.66];
+const KEY_LIGHT_COLOR: [f32; 3] = [2.6, 2.5, 2.3];
+const SKY_COLOR: [f32; 3] = [0.55, 0.6, 0.7];
+const GROUND_COLOR: [f32; 3] = [0.2, 0.18, 0.16];
+
+#[derive(Debug, Clone, Copy)]
+pub enum PreviewShape {
+    Sphere,
+    Plane,
+}
+
+impl FromStr for PreviewShape {
+    type Err = ApplicationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Sphere" => Ok(Self::Sphere),
+            "Plane" => Ok(Self::Plane),
+            _ => Err(ApplicationError::InvalidParameter(s.to_string())),
+        }
+    }
+}
+
+/// How previews are rendered.
+pub struct PreviewSettings {
+    pub shape: PreviewShape,
+    /// Preview width and height in pixels.
+    pub size: u32,
+    /// Convention of the packed normal maps, see `PackSettings::normal_map_type`.
+    pub normal_map_type: NormalMapType,
+}
+
+/// Renders `<name>_preview.png` next to the packed images of every material, or only of `only_materials`.
+pub fn render_previews(
+    pack_cache_path: &Path,
+    force_preview: bool,
+    preview_settings: &PreviewSettings,
+    only_materials: Option<&BTreeSet<String>>,
+    failures: &FailureLog,
+) -> Result<(), ApplicationError> {
+    let material_paths = read_dir(pack_cache_path)
+        .with_context(|| format!("reading {}", pack_cache_path.display()))?
+        .filter_map(|f| f.ok())
+        .map(|f| f.path())
+        .filter(|f| f.join("Material.json").is_file())
+        .filter(|f| {
+            only_materials
+                .is_none_or(|materials| materials.contains(&*f.file_name().unwrap_or_default().to_string_lossy()))
+        })
+        .collect::<Vec<PathBuf>>();
+
+    log::info!("PREVIEW {} materials", material_paths.len());
+    let progress = StageProgress::new("preview", material_paths.len() as u64);
+
+    let manifest = BuildManifest::load(pack_cache_path)?;
+    let result = material_paths
+        .par_iter()
+        .map(|material_path| {
+            let material_name = material_path.file_name().unwrap_or_default().to_string_lossy();
+            let result = failures.handle(
+                "preview",
+                &material_name,
+                render_material_preview(&manifest, material_path, preview_settings, force_preview).map(|_| ()),
+            );
+            progress.inc(&material_name);
+            result
+        })
+        .collect::<Result<Vec<_>, ApplicationError>>();
+    manifest.save()?;
+    result?;
+
+    progress.finish();
+    Ok(())
+}
+
+/// Returns true when the preview of a material was rendered from other inputs or settings than the current ones.
+pub fn is_outdated(
+    manifest: &BuildManifest,
+    material_path: &Path,
+    preview_settings: &PreviewSettings,
+) -> Result<bool, ApplicationError> {
+    let material_name = material_path.file_name().unwrap_or_default().to_string_lossy();
+    let preview_path = material_path.join(format!("{}_preview.png", material_name));
+    Ok(manifest.is_recorded(&preview_path)
+        && !manifest.is_up_to_date(&preview_path, &input_hash(manifest, material_path, preview_settings)?))
+}
+
+fn input_hash(
+    manifest: &BuildManifest,
+    material_path: &Path,
+    preview_settings: &PreviewSettings,
+) -> Result<String, ApplicationError> {
+    let material_name = material_path.file_name().unwrap_or_default().to_string_lossy();
+    Ok(manifest
+        .hasher("preview", OUTPUT_VERSION)
+        .file(&material_path.join("Material.json"))?
+        .file(&material_path.join(format!("{}_A.png", material_name)))?
+        .file(&material_path.join(format!("{}_N.png", material_name)))?
+        .file(&material_path.join(format!("{}_MODR.png", material_name)))?
+        .setting("shape", format!("{:?}", preview_settings.shape))
+        .setting("size", preview_settings.size)
+        .setting("normal_map_type", format!("{:?}", preview_settings.normal_map_type))
+        .finish())
+}
+
+/// Renders the preview of one material folder in the pack cache, unless it is up to date.
+pub fn render_material_preview(
+    manifest: &BuildManifest,
+    material_path: &Path,
+    preview_settings: &PreviewSettings,
+    force_preview: bool,
+) -> Result<MaterialOutputs, ApplicationError> {
+    let material_name = material_path.file_name().unwrap_or_default().to_string_lossy();
+    let material_json = material_path.join("Material.json");
+    let albedo_path = material_path.join(format!("{}_A.png", material_name));
+    let normal_path = material_path.join(format!("{}_N.png", material_name));
+    let material_pack_path = material_path.join(format!("{}_MODR.png", material_name));
+    let preview_path = material_path.join(format!("{}_preview.png", material_name));
+
+    let mut outputs = MaterialOutputs::new(material_name.as_ref());
+    let input_hash = input_hash(manifest, material_path, preview_settings)?;
+    if !force_preview && manifest.is_up_to_date(&preview_path, &input_hash) {
+        outputs.push(preview_path, false);
+        return Ok(outputs);
+    }
+
+    log::debug!("PREVIEW {}", material_name);
+
+    let material: serde_json::Value = serde_json::from_str(
+        &read_to_string(&material_json).with_context(|| format!("reading {}", material_json.display()))?,
+    )
+    .with_context(|| format!("parsing {}", material_json.display()))?;
+    let has_map = |map: &str| material.get(map).and_then(|f| f.as_bool()).unwrap_or_default();
+
+    let textures = PreviewTextures {
+        albedo: load_optional(&albedo_path)?,
+        normal: load_optional(&normal_path)?,
+        material_pack: load_optional(&material_pack_path)?,
+        has_alpha: has_map("alpha"),
+        has_metalness: has_map("metalness"),
+        has_roughness: has_map("roughness"),
+        has_ao: has_map("ao"),
+        flip_green: matches!(preview_settings.normal_map_type, NormalMapType::Direct3D),
+    };
+
+    let preview_image = render_preview(&textures, preview_settings.shape, preview_settings.size);
+
+    let temp_file_path = material_path.join("mtldpreview.tmp");
+    preview_image
+        .save_with_format(&temp_file_path, ImageFormat::Png)
+        .with_context(|| format!("writing {}", temp_file_path.display()))?;
+    rename(&temp_file_path, &preview_path).with_context(|| format!("writing {}", preview_path.display()))?;
+    manifest.record(&preview_path, &input_hash);
+    outputs.push(preview_path, true);
+
+    Ok(outputs)
+}
+
+fn load_optional(image_path: &Path) -> Result<Option<RgbaImage>, ApplicationError> {
+    if image_path.exists() {
+        Ok(Some(pack::load_png(image_path)?.into_rgba8()))
+    } else {
+        Ok(None)
+    }
+}
+
+struct PreviewTextures {
+    albedo: Option<RgbaImage>,
+    normal: Option<RgbaImage>,
+    material_pack: Option<RgbaImage>,
+    has_alpha: bool,
+    has_metalness: bool,
+    has_roughness: bool,
+    has_ao: bool,
+    flip_green: bool,
+}
+
+/// Surface point seen through one pixel: texture coordinates and the tangent frame in view space.
+struct SurfacePoint {
+    uv: [f32; 2],
+    normal: [f32; 3],
+    tangent: [f32; 3],
+    bitangent: [f32; 3],
+}
+
+fn render_preview(textures: &PreviewTextures, shape: PreviewShape, size: u32) -> RgbaImage {
+    let samples = size * PREVIEW_SUPERSAMPLING;
+    let key_light_direction = normalize(KEY_LIGHT_DIRECTION);
+
+    let mut preview_image = RgbaImage::new(size, size);
+    for (x, y, pixel) in preview_image.enumerate_pixels_mut() {
+        let mut color = [0.0; 4];
+        for sample_y in 0..PREVIEW_SUPERSAMPLING {
+            for sample_x in 0..PREVIEW_SUPERSAMPLING {
+                // Screen coordinates in [-1, 1] with y pointing up.
+                let screen_x = ((x * PREVIEW_SUPERSAMPLING + sample_x) as f32 + 0.5) / samples as f32 * 2.0 - 1.0;
+                let screen_y = 1.0 - ((y * PREVIEW_SUPERSAMPLING + sample_y) as f32 + 0.5) / samples as f32 * 2.0;
+
+                if let Some(surface) = surface_point(shape, screen_x, screen_y) {
+                    if let Some(sample) = shade(textures, &surface, key_light_direction) {
+                        for (target, source) in color.iter_mut().zip(sample) {
+                            *target += source;
+                        }
+                    }
+                }
+            }
+        }
+
+        // Covered samples are averaged and coverage becomes alpha, so edges blend with whatever is behind the preview.
+        let coverage = color[3] / (PREVIEW_SUPERSAMPLING * PREVIEW_SUPERSAMPLING) as f32;
+        for channel in 0..3 {
+            let value = if color[3] > 0.0 { color[channel] / color[3] } else { 0.0 };
+            pixel[channel] = (linear_to_srgb(tone_map(value)) * 255.0 + 0.5) as u8;
+        }
+        pixel[3] = (coverage * 255.0 + 0.5) as u8;
+    }
+    preview_image
+}
+
+fn surface_point(shape: PreviewShape, screen_x: f32, screen_y: f32) -> Option<SurfacePoint> {
+    match shape {
+        PreviewShape::Sphere => {
+            let radius = 0.95;
+            let (x, y) = (screen_x / radius, screen_y / radius);
+            let z_squared = 1.0 - x * x - y * y;
+            if z_squared < 0.0 {
+                return None;
+            }
+
+            let normal = [x, y, z_squared.sqrt()];
+            let longitude = x.atan2(normal[2]);
+            let latitude = y.clamp(-1.0, 1.0).asin();
+            let tangent = [longitude.cos(), 0.0, -longitude.sin()];
+
+            Some(SurfacePoint {
+                uv: [
+                    (longitude / (2.0 * PI) + 0.5) * PREVIEW_TILING * 2.0,
+                    (0.5 - latitude / PI) * PREVIEW_TILING,
+                ],
+                normal,
+                tangent,
+                bitangent: cross(normal, tangent),
+            })
+        }
+
+        PreviewShape::Plane => {
+            // The plane is tilted back, as if seen from above at a shallow angle.
+            let tilt = 50f32.to_radians();
+            let normal = [0.0, tilt.sin(), tilt.cos()];
+            let tangent = [1.0, 0.0, 0.0];
+
+            Some(SurfacePoint {
+                uv: [
+                    (screen_x * 0.5 + 0.5) * PREVIEW_TILING,
+                    (0.5 - screen_y * 0.5 / tilt.cos()) * PREVIEW_TILING,
+                ],
+                normal,
+                tangent,
+                bitangent: cross(normal, tangent),
+            })
+        }
+    }
+}
+
+/// Returns linear color and coverage of one sample, or None when the alpha test discards it.
+fn shade(textures: &PreviewTextures, surface: &SurfacePoint, key_light_direction: [f32; 3]) -> Option<[f32; 4]> {
+    let albedo = textures
+        .albedo
+        .as_ref()
+        .map(|f| sample(f, surface.uv))
+        .unwrap_or([0.5, 0.5, 0.5, 1.0]);
+    if textures.has_alpha && albedo[3] < 0.5 {
+        return None;
+    }
+    let base_color = [
+        srgb_to_linear(albedo[0]),
+        srgb_to_linear(albedo[1]),
+        srgb_to_linear(albedo[2]),
+    ];
+
+    // Defaults for missing maps match the values the exporters use.
+    let material_pack = textures.material_pack.as_ref().map(|f| sample(f, surface.uv));
+    let metalness = material_pack.filter(|_| textures.has_metalness).map_or(0.0, |f| f[0]);
+    let ao = material_pack.filter(|_| textures.has_ao).map_or(1.0, |f| f[1]);
+    let roughness = material_pack
+        .filter(|_| textures.has_roughness)
+        .map_or(0.5, |f| f[3])
+        .max(0.04);
+
+    let normal = match &textures.normal {
+        Some(normal_image) => {
+            let texel = sample(normal_image, surface.uv);
+            let tangent_x = texel[0] * 2.0 - 1.0;
+            let tangent_y = (texel[1] * 2.0 - 1.0) * if textures.flip_green { -1.0 } else { 1.0 };
+            let tangent_z = texel[2] * 2.0 - 1.0;
+            normalize(add(
+                add(scale(surface.tangent, tangent_x), scale(surface.bitangent, tangent_y)),
+                scale(surface.normal, tangent_z),
+            ))
+        }
+        None => surface.normal,
+    };
+
+    let view = [0.0, 0.0, 1.0];
+    let n_dot_v = dot(normal, view).max(1e-4);
+    let n_dot_l = dot(normal, key_light_direction).max(0.0);
+    let half = normalize(add(view, key_light_direction));
+    let n_dot_h = dot(normal, half).max(0.0);
+    let v_dot_h = dot(view, half).max(0.0);
+
+    // GGX distribution with Smith-Schlick visibility and Schlick Fresnel.
+    let alpha = roughness * roughness;
+    let alpha_squared = alpha * alpha;
+    let distribution_denominator = n_dot_h * n_dot_h * (alpha_squared - 1.0) + 1.0;
+    let distribution = alpha_squared / (PI * distribution_denominator * distribution_denominator);
+    let k = alpha * 0.5;
+    let visibility = 0.25 / ((n_dot_l * (1.0 - k) + k) * (n_dot_v * (1.0 - k) + k));
+
+    // Ambient light from a sky and ground hemisphere, reflections get blurrier with roughness.
+    let reflection = sub(scale(normal, 2.0 * dot(normal, view)), view);
+    let sky_amount = |direction: [f32; 3]| direction[1] * 0.5 + 0.5;
+    let diffuse_sky_amount = sky_amount(normal);
+    let reflection_sky_amount = sky_amount(reflection) + (0.5 - sky_amount(reflection)) * roughness;
+
+    let mut color = [0.0; 3];
+    for channel in 0..3 {
+        let f0 = 0.04 + (base_color[channel] - 0.04) * metalness;
+        let fresnel = f0 + (1.0 - f0) * (1.0 - v_dot_h).powi(5);
+        let diffuse = base_color[channel] * (1.0 - metalness) / PI;
+        let specular = distribution * visibility * fresnel;
+        let key_light = (diffuse * (1.0 - fresnel) + specular) * KEY_LIGHT_COLOR[channel] * n_dot_l;
+
+        let ambient = |amount: f32| GROUND_COLOR[channel] + (SKY_COLOR[channel] - GROUND_COLOR[channel]) * amount;
+        let ambient_fresnel = f0 + ((1.0 - roughness).max(f0) - f0) * (1.0 - n_dot_v).powi(5);
+        let ambient_diffuse = base_color[channel] * (1.0 - metalness) * ambient(diffuse_sky_amount);
+        let ambient_specular = ambient_fresnel * ambient(reflection_sky_amount);
+
+        color[channel] = key_light + (ambient_diffuse + ambient_specular) * ao;
+    }
+
+    Some([color[0], color[1], color[2], 1.0])
+}
+
+/// Bilinear sample with wrapping, values in [0, 1].
+fn sample(image: &RgbaImage, uv: [f32; 2]) -> [f32; 4] {
+    let (width, height) = image.dimensions();
+    let x = uv[0].rem_euclid(1.0) * width as f32 - 0.5;
+    let y = uv[1].rem_euclid(1.0) * height as f32 - 0.5;
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fraction_x, fraction_y) = (x - x0, y - y0);
+
+    let texel = |texel_x: f32, texel_y: f32| {
+        let texel_x = (texel_x as i64).rem_euclid(width as i64) as u32;
+        let texel_y = (texel_y as i64).rem_euclid(height as i64) as u32;
+        image.get_pixel(texel_x, texel_y).0
+    };
+    let (top_left, top_right) = (texel(x0, y0), texel(x0 + 1.0, y0));
+    let (bottom_left, bottom_right) = (texel(x0, y0 + 1.0), texel(x0 + 1.0, y0 + 1.0));
+
+    let mut result = [0.0; 4];
+    for channel in 0..4 {
+        let top = top_left[channel] as f32 + (top_right[channel] as f32 - top_left[channel] as f32) * fraction_x;
+        let bottom =
+            bottom_left[channel] as f32 + (bottom_right[channel] as f32 - bottom_left[channel] as f32) * fraction_x;
+        result[channel] = (top + (bottom - top) * fraction_y) / 255.0;
+    }
+    result
+}
+
+/// Narkowicz's ACES filmic curve approximation.
+fn tone_map(value: f32) -> f32 {
+    let value = value * 0.8;
+    ((value * (2.51 * value + 0.03)) / (value * (2.43 * value + 0.59) + 0.14)).clamp(0.0, 1.0)
+}
+
+fn add(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+
+fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn scale(a: [f32; 3], b: f32) -> [f32; 3] {
+    [a[0] * b, a[1] * b, a[2] * b]
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn normalize(a: [f32; 3]) -> [f32; 3] {
+    scale(a, 1.0 / dot(a, a).sqrt().max(1e-8))
+}
@@ -0,0 +1,35 @@
+// Copyright (c) 2021 Kyrylo Bazhenov
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use handlebars::{handlebars_helper, no_escape, Handlebars};
+use std::fs::*;
+use std::path::*;
+
+use crate::application_error::*;
+
+// JSON string literals are valid Python string literals, Rust's debug formatting isn't, e.g. for `\u{1b}`.
+handlebars_helper!(python_string: |s: str| serde_json::to_string(s).unwrap_or_default());
+
+/// Creates a template registry for exporter templates.
+///
+/// Templates are rendered in strict mode, so referencing a variable that is missing from the context fails with
+/// an error naming the template file and line. Use `{{#if}}` to emit optional sections.
+pub fn create_registry() -> Handlebars<'static> {
+    let mut registry = Handlebars::new();
+    registry.set_strict_mode(true);
+    registry.register_escape_fn(no_escape);
+    registry.register_helper("python_string", Box::new(python_string));
+    registry
+}
+
+/// Registers a template file under its path, which is then used as the template name in error messages.
+pub fn register_template(registry: &mut Handlebars<'static>, template_path: &Path) -> Result<String, ApplicationError> {
+    let template_name = template_path.display().to_string();
+    registry.register_template_string(
+        &template_name,
+        read_to_string(template_path).with_context(|| format!("reading {}", template_path.display()))?,
+    )?;
+    Ok(template_name)
+}
@@ -3,147 +3,900 @@
 // This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
 // If a copy of the MPL was not distributed with this file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use handlebars::Handlebars;
+use image::*;
+use itertools::izip;
 use std::fs::*;
+use std::io::Write;
 use std::path::*;
+use std::str::FromStr;
 use uuid::*;
 
 use crate::application_error::*;
+use crate::exporter::*;
+use crate::logging::*;
+use crate::manifest::*;
+use crate::material::*;
+use crate::pack;
+use crate::template;
 
-pub fn generate_unity(
-    pack_cache_path: &Path,
+/// Output version of exported Unity assets, bump when the exporter writes different files for the same inputs.
+const OUTPUT_VERSION: u32 = 1;
+
+const NATIVE_META_TEMPLATE: &str = concat!(
+    "fileFormatVersion: 2\n",
+    "guid: {{guid}}\n",
+    "NativeFormatImporter:\n",
+    "  externalObjects: {}\n",
+    "  mainObjectFileID: {{main_object_file_id}}\n",
+    "  userData: \n",
+    "  assetBundleName: \n",
+    "  assetBundleVariant: \n",
+);
+
+const MATERIAL_FILE_ID: i64 = 2100000;
+const TERRAIN_LAYER_FILE_ID: i64 = 8574412962073106934;
+
+/// Terrain layers use the HDRP mask map layout with height in the blue channel, for all pipelines.
+const TERRAIN_MASK_LAYOUT: UnityTextureLayout = UnityTextureLayout {
+    name: "mask",
+    suffix: "TerrainMask",
+    channels: [
+        UnityChannel::Metalness,
+        UnityChannel::Ao,
+        UnityChannel::Displacement,
+        UnityChannel::Smoothness,
+    ],
+    keywords: &[],
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum UnityPipeline {
+    BuiltIn,
+    Urp,
+    Hdrp,
+}
+
+impl FromStr for UnityPipeline {
+    type Err = ApplicationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "BuiltIn" => Ok(Self::BuiltIn),
+            "URP" => Ok(Self::Urp),
+            "HDRP" => Ok(Self::Hdrp),
+            _ => Err(ApplicationError::InvalidParameter(s.to_string())),
+        }
+    }
+}
+
+/// Source of a single channel in a texture generated from the packed `_MODR` image.
+#[derive(Debug, Clone, Copy)]
+enum UnityChannel {
+    Metalness,
+    Ao,
+    Displacement,
+    Smoothness,
+    Constant(u8),
+}
+
+/// Where the value of a channel in a generated texture comes from.
+#[derive(Debug, Clone, Copy)]
+enum ChannelSource {
+    /// A `_MODR` channel, inverted e.g. to turn roughness into smoothness.
+    Image { index: usize, invert: bool },
+    /// The same value in every texel, used when the material has no data for the channel.
+    Constant(u8),
+}
+
+impl ChannelSource {
+    fn has_data(self) -> bool {
+        matches!(self, Self::Image { .. })
+    }
+}
+
+/// Texture that a pipeline needs in addition to the albedo and normal maps.
+struct UnityTextureLayout {
+    /// Key in the `textures` object of the material template context.
+    name: &'static str,
+    suffix: &'static str,
+    channels: [UnityChannel; 4],
+    keywords: &'static [&'static str],
+}
+
+impl UnityPipeline {
+    fn default_material_template(self) -> &'static str {
+        match self {
+            Self::BuiltIn => "templates/unity_builtin_material.template",
+            Self::Urp => "templates/unity_urp_material.template",
+            Self::Hdrp => "templates/unity_hdrp_material.template",
+        }
+    }
+
+    fn normal_keywords(self) -> &'static [&'static str] {
+        match self {
+            Self::BuiltIn | Self::Urp => &["_NORMALMAP"],
+            Self::Hdrp => &["_NORMALMAP", "_NORMALMAP_TANGENT_SPACE"],
+        }
+    }
+
+    /// Decals are alpha blended instead of alpha tested.
+    fn decal_keywords(self) -> &'static [&'static str] {
+        match self {
+            Self::BuiltIn => &["_ALPHABLEND_ON"],
+            Self::Urp => &["_SURFACE_TYPE_TRANSPARENT"],
+            Self::Hdrp => &[
+                "_SURFACE_TYPE_TRANSPARENT",
+                "_BLENDMODE_ALPHA",
+                "_ENABLE_FOG_ON_TRANSPARENT",
+            ],
+        }
+    }
+
+    /// The built-in Standard shader and URP Lit turn off culling with a property alone.
+    fn double_sided_keywords(self) -> &'static [&'static str] {
+        match self {
+            Self::BuiltIn | Self::Urp => &[],
+            Self::Hdrp => &["_DOUBLESIDED_ON"],
+        }
+    }
+
+    fn emission_keywords(self) -> &'static [&'static str] {
+        match self {
+            Self::BuiltIn | Self::Urp => &["_EMISSION"],
+            Self::Hdrp => &["_EMISSIVE_COLOR_MAP"],
+        }
+    }
+
+    fn texture_layouts(self) -> &'static [UnityTextureLayout] {
+        match self {
+            Self::BuiltIn => &[UnityTextureLayout {
+                name: "metallic_gloss",
+                suffix: "MetallicGloss",
+                channels: [
+                    UnityChannel::Metalness,
+                    UnityChannel::Ao,
+                    UnityChannel::Constant(0),
+                    UnityChannel::Smoothness,
+                ],
+                keywords: &["_METALLICGLOSSMAP"],
+            }],
+            Self::Urp => &[
+                UnityTextureLayout {
+                    name: "metallic_smoothness",
+                    suffix: "MetallicSmoothness",
+                    channels: [
+                        UnityChannel::Metalness,
+                        UnityChannel::Constant(0),
+                        UnityChannel::Constant(0),
+                        UnityChannel::Smoothness,
+                    ],
+                    keywords: &["_METALLICSPECGLOSSMAP"],
+                },
+                UnityTextureLayout {
+                    name: "occlusion",
+                    suffix: "Occlusion",
+                    channels: [
+                        UnityChannel::Ao,
+                        UnityChannel::Ao,
+                        UnityChannel::Ao,
+                        UnityChannel::Constant(255),
+                    ],
+                    keywords: &["_OCCLUSIONMAP"],
+                },
+            ],
+            Self::Hdrp => &[
+                UnityTextureLayout {
+                    name: "mask",
+                    suffix: "Mask",
+                    channels: [
+                        UnityChannel::Metalness,
+                        UnityChannel::Ao,
+                        UnityChannel::Constant(255),
+                        UnityChannel::Smoothness,
+                    ],
+                    keywords: &["_MASKMAP"],
+                },
+                UnityTextureLayout {
+                    name: "height",
+                    suffix: "Height",
+                    channels: [
+                        UnityChannel::Displacement,
+                        UnityChannel::Displacement,
+                        UnityChannel::Displacement,
+                        UnityChannel::Constant(255),
+                    ],
+                    keywords: &["_HEIGHTMAP", "_PIXEL_DISPLACEMENT"],
+                },
+            ],
+        }
+    }
+}
+
+impl UnityChannel {
+    /// Returns the `_MODR` channel with an invert flag, or the value used when the material has no data for it.
+    fn source(self, material: &serde_json::Value) -> ChannelSource {
+        let (index, invert) = match self {
+            Self::Metalness => (0, false),
+            Self::Ao => (1, false),
+            Self::Displacement => (2, false),
+            Self::Smoothness => (3, true),
+            Self::Constant(value) => return ChannelSource::Constant(value),
+        };
+
+        let channel = &pack::MATERIAL_PACK_LAYOUT[index];
+        if material.get(channel.map).and_then(|f| f.as_bool()).unwrap_or_default() {
+            ChannelSource::Image { index, invert }
+        } else if invert {
+            ChannelSource::Constant(255 - channel.default_byte())
+        } else {
+            ChannelSource::Constant(channel.default_byte())
+        }
+    }
+}
+
+pub struct UnitySettings {
+    pub pipeline: UnityPipeline,
+    pub asset_path: String,
+    pub texture_template: PathBuf,
+    pub material_template: Option<PathBuf>,
+    pub terrain_categories: Vec<String>,
+    pub terrain_tile_size: f32,
+    pub terrain_layer_template: PathBuf,
+}
+
+impl UnitySettings {
+    fn material_template_path(&self) -> PathBuf {
+        self.material_template
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(self.pipeline.default_material_template()))
+    }
+}
+
+/// Generates Unity3D materials, textures, terrain layers and meta files from packed materials.
+pub struct UnityExporter {
+    unity_cache_path: PathBuf,
+    unity_settings: UnitySettings,
+    force_generate_unity: bool,
+    templates: Handlebars<'static>,
+    manifest: BuildManifest,
+}
+
+impl UnityExporter {
+    /// Registers the templates of `unity_settings` and loads the build manifest of the Unity3D cache.
+    pub fn new(
+        unity_cache_path: &Path,
+        unity_settings: UnitySettings,
+        force_generate_unity: bool,
+    ) -> Result<Self, ApplicationError> {
+        create_dir_all(unity_cache_path).with_context(|| format!("creating {}", unity_cache_path.display()))?;
+
+        let mut templates = template::create_registry();
+        template::register_template(&mut templates, &unity_settings.texture_template)?;
+        template::register_template(&mut templates, &unity_settings.material_template_path())?;
+        if !unity_settings.terrain_categories.is_empty() {
+            template::register_template(&mut templates, &unity_settings.terrain_layer_template)?;
+        }
+        templates.register_template_string("unity_native_meta", NATIVE_META_TEMPLATE)?;
+
+        Ok(Self {
+            unity_cache_path: unity_cache_path.to_owned(),
+            unity_settings,
+            force_generate_unity,
+            templates,
+            manifest: BuildManifest::load(unity_cache_path)?,
+        })
+    }
+}
+
+impl Exporter for UnityExporter {
+    fn stage(&self) -> &'static str {
+        "unity"
+    }
+
+    fn export_material(&self, material_path: &Path) -> Result<MaterialOutputs, ApplicationError> {
+        generate_single_material(
+            &self.manifest,
+            &self.templates,
+            material_path,
+            self.force_generate_unity,
+            &self.unity_cache_path,
+            &self.unity_settings,
+        )
+    }
+
+    fn finish(&self) -> Result<(), ApplicationError> {
+        self.manifest.save()
+    }
+}
+
+fn generate_single_material(
+    manifest: &BuildManifest,
+    templates: &Handlebars,
+    material_path: &Path,
     force_generate_unity: bool,
     unity_cache_path: &Path,
-    unity_texture_template: &Path,
-    unity_material_template: &Path,
-) -> Result<(), ApplicationError> {
-    create_dir_all(unity_cache_path)?;
-
-    let texture_template = read_to_string(unity_texture_template)?;
-    let material_template = read_to_string(unity_material_template)?;
-
-    for dir in read_dir(pack_cache_path)? {
-        let dir = dir?;
-        let material_path = dir.path();
-
-        if let Some(material_dir_name) = material_path.file_stem() {
-            let material_json = material_path.join("Material.json");
-            if material_json.exists() {
-                let material: serde_json::Value = serde_json::from_str(&read_to_string(&material_json)?)?;
-
-                if let Some(material_name) = material.get("name").and_then(|f| f.as_str()) {
-                    if material_name == material_dir_name {
-                        println!("UNITY {}", material_name);
-
-                        let target_path = unity_cache_path.join(material_name);
-                        create_dir_all(&target_path)?;
-
-                        let has_albedo = material.get("albedo").and_then(|f| f.as_bool()).unwrap_or_default();
-                        let has_opacity = material.get("opacity").and_then(|f| f.as_bool()).unwrap_or_default();
-                        let has_normal = material.get("normal").and_then(|f| f.as_bool()).unwrap_or_default();
-                        let has_metalness = material.get("metalness").and_then(|f| f.as_bool()).unwrap_or_default();
-                        let has_roughness = material.get("roughness").and_then(|f| f.as_bool()).unwrap_or_default();
-                        let has_ao = material.get("ao").and_then(|f| f.as_bool()).unwrap_or_default();
-                        let has_displacement = material
-                            .get("displacement")
-                            .and_then(|f| f.as_bool())
-                            .unwrap_or_default();
-
-                        let has_material_pack = has_metalness || has_roughness || has_ao || has_displacement;
-
-                        let material_file_path = target_path.join(format!("{}.mat", material_name));
-                        if force_generate_unity || !material_file_path.exists() {
-                            let albedo_uuid = Uuid::new_v4().to_string().replace('-', "");
-                            if has_albedo {
-                                let albedo_path = target_path.join(format!("{}_A.png.meta", material_name));
-                                write(
-                                    &albedo_path,
-                                    texture_template
-                                        .replace("$$TEXTURE_GUID$$", &albedo_uuid)
-                                        .replace("$$TEXTURE_SRGB$$", "1")
-                                        .replace("$$TEXTURE_ALPHA$$", if has_opacity { "1" } else { "0" })
-                                        .replace("$$TEXTURE_OPACITY$$", if has_opacity { "1" } else { "0" })
-                                        .replace("$$TEXTURE_TYPE$$", "0"),
-                                )?;
-                            }
+    unity_settings: &UnitySettings,
+) -> Result<MaterialOutputs, ApplicationError> {
+    let unity_pipeline = unity_settings.pipeline;
+    let material_template_path = unity_settings.material_template_path();
+    let texture_template = unity_settings.texture_template.display().to_string();
+    let material_template = material_template_path.display().to_string();
+    let terrain_layer_template = unity_settings.terrain_layer_template.display().to_string();
 
-                            let normal_uuid = Uuid::new_v4().to_string().replace('-', "");
-                            if has_normal {
-                                let normal_path = target_path.join(format!("{}_N.png.meta", material_name));
-                                write(
-                                    &normal_path,
-                                    texture_template
-                                        .replace("$$TEXTURE_GUID$$", &normal_uuid)
-                                        .replace("$$TEXTURE_SRGB$$", "0")
-                                        .replace("$$TEXTURE_ALPHA$$", "0")
-                                        .replace("$$TEXTURE_OPACITY$$", "0")
-                                        .replace("$$TEXTURE_TYPE$$", "1"),
-                                )?;
+    let mut outputs = MaterialOutputs::new(material_path.file_name().unwrap_or_default().to_string_lossy());
+    if let Some(material_dir_name) = material_path.file_stem() {
+        let material_json = material_path.join("Material.json");
+        if material_json.exists() {
+            let material: serde_json::Value = serde_json::from_str(
+                &read_to_string(&material_json).with_context(|| format!("reading {}", material_json.display()))?,
+            )
+            .with_context(|| format!("parsing {}", material_json.display()))?;
+
+            if let Some(material_name) = material.get("name").and_then(|f| f.as_str()) {
+                if material_name == material_dir_name {
+                    log::debug!("UNITY {}", material_name);
+
+                    let target_path = unity_cache_path.join(material_name);
+                    create_dir_all(&target_path).with_context(|| format!("creating {}", target_path.display()))?;
+
+                    let has_albedo = material.get("albedo").and_then(|f| f.as_bool()).unwrap_or_default();
+                    let has_alpha = material.get("alpha").and_then(|f| f.as_bool()).unwrap_or_default();
+                    let has_normal = material.get("normal").and_then(|f| f.as_bool()).unwrap_or_default();
+                    let has_emission = material.get("emission").and_then(|f| f.as_bool()).unwrap_or_default();
+
+                    // Decals use an alpha blended preset, atlas sprites like leaves are seen from both sides.
+                    let material_type = material.get("type").and_then(|f| f.as_str()).unwrap_or_default();
+                    let decal = material_type == "Decal";
+                    let double_sided = material_type == "Atlas";
+
+                    let asset_context = |file_name: &str| {
+                        serde_json::json!({
+                            "guid": asset_guid(file_name),
+                            "file_name": file_name,
+                            "path": format!("{}/{}/{}", unity_settings.asset_path, material_name, file_name),
+                        })
+                    };
+                    let texture_meta =
+                        |texture_context: &serde_json::Value, srgb: bool, alpha: bool, opacity: bool, normal: bool| {
+                            templates.render(
+                                &texture_template,
+                                &serde_json::json!({
+                                    "guid": texture_context["guid"],
+                                    "srgb": srgb,
+                                    "alpha": alpha,
+                                    "opacity": opacity,
+                                    // Cutout albedo keeps its alpha test coverage in every mip, like in other formats.
+                                    "alpha_test": opacity && !decal,
+                                    "normal": normal,
+                                }),
+                            )
+                        };
+
+                    let material_pack_path = material_path.join(format!("{}_MODR.png", material_name));
+                    let mut material_pack_image = None;
+
+                    let mut textures = serde_json::Map::new();
+
+                    if has_albedo {
+                        let albedo_file_name = format!("{}_A.png", material_name);
+                        textures.insert("albedo".to_owned(), asset_context(&albedo_file_name));
+                    }
+
+                    if has_normal {
+                        let normal_file_name = format!("{}_N.png", material_name);
+                        textures.insert("normal".to_owned(), asset_context(&normal_file_name));
+                    }
+
+                    if has_emission {
+                        let emission_file_name = format!("{}_E.png", material_name);
+                        textures.insert("emission".to_owned(), asset_context(&emission_file_name));
+                    }
+
+                    let input_hash = input_hasher(manifest, material_path, material_name, unity_settings)?;
+
+                    let material_file_path = target_path.join(format!("{}.mat", material_name));
+                    let material_input_hash = input_hash.clone().file(&material_template_path)?.finish();
+                    let material_outdated =
+                        force_generate_unity || !manifest.is_up_to_date(&material_file_path, &material_input_hash);
+                    if material_outdated {
+                        let mut keywords = Vec::new();
+
+                        if let Some(albedo_context) = textures.get("albedo") {
+                            let albedo_path =
+                                target_path.join(albedo_context["file_name"].as_str().unwrap_or_default());
+                            copy(
+                                material_path.join(albedo_path.file_name().unwrap_or_default()),
+                                &albedo_path,
+                            )
+                            .with_context(|| format!("writing {}", albedo_path.display()))?;
+                            write(
+                                albedo_path.with_extension("png.meta"),
+                                texture_meta(albedo_context, true, has_alpha, has_alpha, false)?,
+                            )
+                            .with_context(|| format!("writing {}.meta", albedo_path.display()))?;
+                            outputs.push(albedo_path.with_extension("png.meta"), true);
+                            outputs.push(albedo_path, true);
+                        }
+                        if decal {
+                            keywords.extend_from_slice(unity_pipeline.decal_keywords());
+                        } else if has_alpha {
+                            keywords.push("_ALPHATEST_ON");
+                        }
+                        if double_sided {
+                            keywords.extend_from_slice(unity_pipeline.double_sided_keywords());
+                        }
+
+                        if let Some(normal_context) = textures.get("normal") {
+                            let normal_path =
+                                target_path.join(normal_context["file_name"].as_str().unwrap_or_default());
+                            copy(
+                                material_path.join(normal_path.file_name().unwrap_or_default()),
+                                &normal_path,
+                            )
+                            .with_context(|| format!("writing {}", normal_path.display()))?;
+                            write(
+                                normal_path.with_extension("png.meta"),
+                                texture_meta(normal_context, false, false, false, true)?,
+                            )
+                            .with_context(|| format!("writing {}.meta", normal_path.display()))?;
+                            outputs.push(normal_path.with_extension("png.meta"), true);
+                            outputs.push(normal_path, true);
+                            keywords.extend_from_slice(unity_pipeline.normal_keywords());
+                        }
+
+                        if let Some(emission_context) = textures.get("emission") {
+                            let emission_path =
+                                target_path.join(emission_context["file_name"].as_str().unwrap_or_default());
+                            copy(
+                                material_path.join(emission_path.file_name().unwrap_or_default()),
+                                &emission_path,
+                            )
+                            .with_context(|| format!("writing {}", emission_path.display()))?;
+                            write(
+                                emission_path.with_extension("png.meta"),
+                                texture_meta(emission_context, true, false, false, false)?,
+                            )
+                            .with_context(|| format!("writing {}.meta", emission_path.display()))?;
+                            outputs.push(emission_path.with_extension("png.meta"), true);
+                            outputs.push(emission_path, true);
+                            keywords.extend_from_slice(unity_pipeline.emission_keywords());
+                        }
+
+                        let mut material_textures = textures.clone();
+                        if material_pack_path.exists() {
+                            let material_pack_image =
+                                material_pack_image.insert(pack::load_png(&material_pack_path)?.into_rgba8());
+
+                            for texture_layout in unity_pipeline.texture_layouts() {
+                                let channel_sources = texture_layout.channels.map(|channel| channel.source(&material));
+                                if channel_sources.iter().any(|source| source.has_data()) {
+                                    let texture_file_name = format!("{}_{}.png", material_name, texture_layout.suffix);
+                                    let texture_path = target_path.join(&texture_file_name);
+                                    let texture_context = asset_context(&texture_file_name);
+
+                                    write_layout_texture(material_pack_image, &channel_sources, &texture_path)?;
+                                    write(
+                                        texture_path.with_extension("png.meta"),
+                                        texture_meta(&texture_context, false, true, false, false)?,
+                                    )
+                                    .with_context(|| format!("writing {}.meta", texture_path.display()))?;
+                                    outputs.push(texture_path.with_extension("png.meta"), true);
+                                    outputs.push(texture_path, true);
+
+                                    keywords.extend_from_slice(texture_layout.keywords);
+                                    material_textures.insert(texture_layout.name.to_owned(), texture_context);
+                                }
                             }
+                        }
 
-                            let material_pack_uuid = Uuid::new_v4().to_string().replace('-', "");
-                            if has_material_pack {
-                                let material_pack_path = target_path.join(format!("{}_MODR.png.meta", material_name));
+                        write(
+                            &material_file_path,
+                            templates.render(
+                                &material_template,
+                                &serde_json::json!({
+                                    "material": material,
+                                    "keywords": keywords,
+                                    "textures": material_textures,
+                                    "decal": decal,
+                                    "double_sided": double_sided,
+                                }),
+                            )?,
+                        )
+                        .with_context(|| format!("writing {}", material_file_path.display()))?;
+                        write(
+                            material_file_path.with_extension("mat.meta"),
+                            templates.render(
+                                "unity_native_meta",
+                                &serde_json::json!({
+                                    "guid": asset_guid(&format!("{}.mat", material_name)),
+                                    "main_object_file_id": MATERIAL_FILE_ID,
+                                }),
+                            )?,
+                        )
+                        .with_context(|| format!("writing {}.meta", material_file_path.display()))?;
+                        manifest.record(&material_file_path, &material_input_hash);
+                        outputs.push(material_file_path.with_extension("mat.meta"), true);
+                    }
+                    outputs.push(material_file_path, material_outdated);
+
+                    // Previews are optional, they are copied as plain sRGB textures for use in custom browsers.
+                    let preview_file_name = format!("{}_preview.png", material_name);
+                    let preview_source_path = material_path.join(&preview_file_name);
+                    if preview_source_path.exists() {
+                        let preview_path = target_path.join(&preview_file_name);
+                        let preview_input_hash = manifest
+                            .hasher("unity", OUTPUT_VERSION)
+                            .setting("asset_path", &unity_settings.asset_path)
+                            .file(&unity_settings.texture_template)?
+                            .file(&preview_source_path)?
+                            .finish();
+                        let preview_outdated =
+                            force_generate_unity || !manifest.is_up_to_date(&preview_path, &preview_input_hash);
+                        if preview_outdated {
+                            copy(&preview_source_path, &preview_path)
+                                .with_context(|| format!("writing {}", preview_path.display()))?;
+                            write(
+                                preview_path.with_extension("png.meta"),
+                                texture_meta(&asset_context(&preview_file_name), true, true, false, false)?,
+                            )
+                            .with_context(|| format!("writing {}.meta", preview_path.display()))?;
+                            manifest.record(&preview_path, &preview_input_hash);
+                            outputs.push(preview_path.with_extension("png.meta"), true);
+                        }
+                        outputs.push(preview_path, preview_outdated);
+                    }
+
+                    let material_category = material.get("category").and_then(|f| f.as_str()).unwrap_or_default();
+                    if unity_settings
+                        .terrain_categories
+                        .iter()
+                        .any(|category| category == material_category)
+                    {
+                        let terrain_layer_path = target_path.join(format!("{}.terrainlayer", material_name));
+                        let terrain_layer_input_hash = input_hash
+                            .file(&unity_settings.terrain_layer_template)?
+                            .setting("terrain_tile_size", unity_settings.terrain_tile_size)
+                            .finish();
+                        let terrain_layer_outdated = force_generate_unity
+                            || !manifest.is_up_to_date(&terrain_layer_path, &terrain_layer_input_hash);
+                        if terrain_layer_outdated {
+                            let channel_sources = TERRAIN_MASK_LAYOUT.channels.map(|channel| channel.source(&material));
+
+                            let mut terrain_textures = textures.clone();
+                            if material_pack_path.exists() && channel_sources.iter().any(|source| source.has_data()) {
+                                let material_pack_image = match material_pack_image.take() {
+                                    Some(material_pack_image) => material_pack_image,
+                                    None => pack::load_png(&material_pack_path)?.into_rgba8(),
+                                };
+
+                                let texture_file_name = format!("{}_{}.png", material_name, TERRAIN_MASK_LAYOUT.suffix);
+                                let texture_path = target_path.join(&texture_file_name);
+                                let texture_context = asset_context(&texture_file_name);
+
+                                write_layout_texture(&material_pack_image, &channel_sources, &texture_path)?;
                                 write(
-                                    &material_pack_path,
-                                    texture_template
-                                        .replace("$$TEXTURE_GUID$$", &material_pack_uuid)
-                                        .replace("$$TEXTURE_SRGB$$", "0")
-                                        .replace("$$TEXTURE_ALPHA$$", "1")
-                                        .replace("$$TEXTURE_OPACITY$$", "0")
-                                        .replace("$$TEXTURE_TYPE$$", "0"),
-                                )?;
-                            }
+                                    texture_path.with_extension("png.meta"),
+                                    texture_meta(&texture_context, false, true, false, false)?,
+                                )
+                                .with_context(|| format!("writing {}.meta", texture_path.display()))?;
+                                outputs.push(texture_path.with_extension("png.meta"), true);
+                                outputs.push(texture_path, true);
 
-                            let mut keywords = String::new();
-                            if has_opacity {
-                                keywords.push_str(" _ALPHATEST_ON");
-                            }
-                            if has_normal {
-                                keywords.push_str(" _NORMALMAP");
-                            }
-                            if has_roughness || has_metalness {
-                                keywords.push_str(" _METALLICSPECGLOSSMAP");
-                            }
-                            if has_ao {
-                                keywords.push_str(" _OCCLUSIONMAP");
+                                terrain_textures.insert(TERRAIN_MASK_LAYOUT.name.to_owned(), texture_context);
                             }
 
+                            // Channels without data are remapped to their default value, so the terrain shader
+                            // ignores whatever the mask map contains there.
+                            let remap = channel_sources.map(|source| match source {
+                                ChannelSource::Image { .. } => (0.0, 1.0),
+                                ChannelSource::Constant(default_value) => {
+                                    let default_value = default_value as f64 / 255.0;
+                                    (default_value, default_value)
+                                }
+                            });
+
                             write(
-                                &material_file_path,
-                                material_template
-                                    .replace("$$MATERIAL_NAME$$", material_name)
-                                    .replace(
-                                        "$$SHADER_KEYWORDS$$",
-                                        if keywords.is_empty() { "" } else { &keywords[1..] },
-                                    )
-                                    .replace(
-                                        "$$RENDER_TYPE$$",
-                                        if has_opacity { "TransparentCutout" } else { "Opaque" },
-                                    )
-                                    .replace("$$COLOR_TEXTURE$$", &format_filename(has_albedo, &albedo_uuid))
-                                    .replace("$$NORMAL_TEXTURE$$", &format_filename(has_normal, &normal_uuid))
-                                    .replace(
-                                        "$$METALLIC_GLOSS_TEXTURE$$",
-                                        &format_filename(has_roughness || has_metalness, &material_pack_uuid),
-                                    )
-                                    .replace("$$AO_TEXTURE$$", &format_filename(has_ao, &material_pack_uuid))
-                                    .replace("$$ALPHA_CLIP$$", if has_opacity { "1" } else { "0" }),
-                            )?;
+                                &terrain_layer_path,
+                                templates.render(
+                                    &terrain_layer_template,
+                                    &serde_json::json!({
+                                        "material": material,
+                                        "textures": terrain_textures,
+                                        "tile_size": unity_settings.terrain_tile_size,
+                                        "mask_remap_min": {
+                                            "r": remap[0].0,
+                                            "g": remap[1].0,
+                                            "b": remap[2].0,
+                                            "a": remap[3].0,
+                                        },
+                                        "mask_remap_max": {
+                                            "r": remap[0].1,
+                                            "g": remap[1].1,
+                                            "b": remap[2].1,
+                                            "a": remap[3].1,
+                                        },
+                                    }),
+                                )?,
+                            )
+                            .with_context(|| format!("writing {}", terrain_layer_path.display()))?;
+                            write(
+                                terrain_layer_path.with_extension("terrainlayer.meta"),
+                                templates.render(
+                                    "unity_native_meta",
+                                    &serde_json::json!({
+                                        "guid": asset_guid(&format!("{}.terrainlayer", material_name)),
+                                        "main_object_file_id": TERRAIN_LAYER_FILE_ID,
+                                    }),
+                                )?,
+                            )
+                            .with_context(|| format!("writing {}.meta", terrain_layer_path.display()))?;
+                            manifest.record(&terrain_layer_path, &terrain_layer_input_hash);
+                            outputs.push(terrain_layer_path.with_extension("terrainlayer.meta"), true);
                         }
-                    } else {
-                        println!("WARN: {:?} != {}", material_dir_name, material_name);
+                        outputs.push(terrain_layer_path, terrain_layer_outdated);
                     }
+                } else {
+                    log::warn!("{:?} != {}", material_dir_name, material_name);
                 }
             }
         }
     }
+    Ok(outputs)
+}
+
+/// Lists generated files of a packed material that were built from other inputs or settings than the current ones: the
+/// whole material folder when the material is outdated, otherwise its terrain layer files when the material category is
+/// no longer a terrain category.
+pub fn outdated_outputs(
+    manifest: &BuildManifest,
+    material_path: &Path,
+    unity_cache_path: &Path,
+    unity_settings: &UnitySettings,
+) -> Result<Vec<PathBuf>, ApplicationError> {
+    let material_name = material_path.file_name().unwrap_or_default().to_string_lossy();
+    let target_path = unity_cache_path.join(material_name.as_ref());
+
+    let material_file_path = target_path.join(format!("{}.mat", material_name));
+    let material_input_hash = input_hasher(manifest, material_path, &material_name, unity_settings)?
+        .file(&unity_settings.material_template_path())?
+        .finish();
+    if manifest.is_recorded(&material_file_path) && !manifest.is_up_to_date(&material_file_path, &material_input_hash) {
+        return Ok(vec![target_path]);
+    }
+
+    let material_json = material_path.join("Material.json");
+    let material: serde_json::Value = serde_json::from_str(
+        &read_to_string(&material_json).with_context(|| format!("reading {}", material_json.display()))?,
+    )
+    .with_context(|| format!("parsing {}", material_json.display()))?;
+    let material_category = material.get("category").and_then(|f| f.as_str()).unwrap_or_default();
+    if unity_settings
+        .terrain_categories
+        .iter()
+        .any(|category| category == material_category)
+    {
+        return Ok(Vec::new());
+    }
+
+    let terrain_mask_file_name = format!("{}_{}.png", material_name, TERRAIN_MASK_LAYOUT.suffix);
+    Ok([
+        format!("{}.terrainlayer", material_name),
+        format!("{}.terrainlayer.meta", material_name),
+        format!("{}.meta", terrain_mask_file_name),
+        terrain_mask_file_name,
+    ]
+    .into_iter()
+    .map(|file_name| target_path.join(file_name))
+    .filter(|f| f.exists())
+    .collect())
+}
+
+/// Hashes inputs shared by the material and the terrain layer of a packed material.
+fn input_hasher<'a>(
+    manifest: &'a BuildManifest,
+    material_path: &Path,
+    material_name: &str,
+    unity_settings: &UnitySettings,
+) -> Result<InputHasher<'a>, ApplicationError> {
+    manifest
+        .hasher("unity", OUTPUT_VERSION)
+        .setting("pipeline", format!("{:?}", unity_settings.pipeline))
+        .setting("asset_path", &unity_settings.asset_path)
+        .file(&unity_settings.texture_template)?
+        .file(&material_path.join("Material.json"))?
+        .file(&material_path.join(format!("{}_A.png", material_name)))?
+        .file(&material_path.join(format!("{}_N.png", material_name)))?
+        .file(&material_path.join(format!("{}_MODR.png", material_name)))?
+        .file(&material_path.join(format!("{}_E.png", material_name)))
+}
+
+/// Writes texture array layers as a flipbook image with a meta file that imports it as a `Texture2DArray`. Layers are
+/// laid out left to right and top to bottom on the grid closest to square with exactly one cell per layer, Unity3D
+/// imports every cell as a layer.
+pub fn write_array_texture(
+    layers: &[RgbaImage],
+    texture_path: &Path,
+    texture_template: &Path,
+    srgb: bool,
+    normal: bool,
+    alpha_test: bool,
+) -> Result<(), ApplicationError> {
+    let (layer_width, layer_height) = layers.first().map(|f| f.dimensions()).unwrap_or_default();
+    let layer_count = (layers.len() as u32).max(1);
+    let rows = (1..=layer_count)
+        .take_while(|rows| rows * rows <= layer_count)
+        .filter(|rows| layer_count.is_multiple_of(*rows))
+        .last()
+        .unwrap_or(1);
+    let columns = layer_count / rows;
+
+    let mut flipbook_image = RgbaImage::new(layer_width * columns, layer_height * rows);
+    for (layer_index, layer) in layers.iter().enumerate() {
+        let (column, row) = (layer_index as u32 % columns, layer_index as u32 / columns);
+        imageops::replace(
+            &mut flipbook_image,
+            layer,
+            (column * layer_width) as i64,
+            (row * layer_height) as i64,
+        );
+    }
+    flipbook_image
+        .save_with_format(texture_path, ImageFormat::Png)
+        .with_context(|| format!("writing {}", texture_path.display()))?;
+
+    let mut templates = template::create_registry();
+    let texture_template = template::register_template(&mut templates, texture_template)?;
+    let texture_file_name = texture_path.file_name().unwrap_or_default().to_string_lossy();
+    write(
+        texture_path.with_extension("png.meta"),
+        templates.render(
+            &texture_template,
+            &serde_json::json!({
+                "guid": array_asset_guid(&texture_file_name),
+                "srgb": srgb,
+                "alpha": !normal,
+                "opacity": alpha_test,
+                "alpha_test": alpha_test,
+                "normal": normal,
+                "flipbook": {
+                    "rows": rows,
+                    "columns": columns,
+                },
+            }),
+        )?,
+    )
+    .with_context(|| format!("writing {}.meta", texture_path.display()))?;
+    Ok(())
+}
+
+fn write_layout_texture(
+    material_pack_image: &RgbaImage,
+    channel_sources: &[ChannelSource; 4],
+    texture_path: &Path,
+) -> Result<(), ApplicationError> {
+    let mut texture_image = RgbaImage::new(material_pack_image.width(), material_pack_image.height());
+    for (target, source) in izip!(texture_image.pixels_mut(), material_pack_image.pixels()) {
+        for (channel_index, channel_source) in channel_sources.iter().enumerate() {
+            target[channel_index] = match *channel_source {
+                ChannelSource::Image { index, invert: false } => source[index],
+                ChannelSource::Image { index, invert: true } => 255 - source[index],
+                ChannelSource::Constant(value) => value,
+            };
+        }
+    }
+    texture_image
+        .save_with_format(texture_path, ImageFormat::Png)
+        .with_context(|| format!("writing {}", texture_path.display()))?;
     Ok(())
 }
 
-fn format_filename(exists: bool, uuid: &str) -> String {
-    if exists {
-        format!("{{fileID: 2800000, guid: {}, type: 3}}", uuid)
-    } else {
-        "{fileID: 0}".to_string()
+/// Writes every generated asset with a `.meta` file into a gzip'd tar archive that Unity imports as a package.
+pub fn package_unity(
+    unity_cache_path: &Path,
+    unity_package_path: &Path,
+    unity_asset_path: &str,
+) -> Result<(), ApplicationError> {
+    let temp_file_path = unity_package_path.with_extension("tmp");
+    let mut package = tar::Builder::new(GzEncoder::new(
+        File::create(&temp_file_path).with_context(|| format!("writing {}", temp_file_path.display()))?,
+        Compression::default(),
+    ));
+
+    let mut material_paths = read_dir(unity_cache_path)
+        .with_context(|| format!("reading {}", unity_cache_path.display()))?
+        .filter_map(|f| f.ok())
+        .map(|f| f.path())
+        .filter(|f| f.is_dir())
+        .collect::<Vec<_>>();
+    material_paths.sort();
+
+    log::info!("UNITYPACKAGE {} materials", material_paths.len());
+    let progress = StageProgress::new("unitypackage", material_paths.len() as u64);
+
+    for material_path in material_paths {
+        if let Some(material_name) = material_path.file_name().and_then(|f| f.to_str()) {
+            log::debug!("UNITYPACKAGE {}", material_name);
+
+            let mut asset_paths = read_dir(&material_path)
+                .with_context(|| format!("reading {}", material_path.display()))?
+                .filter_map(|f| f.ok())
+                .map(|f| f.path())
+                .filter(|f| f.is_file() && f.extension().is_some_and(|e| e != "meta"))
+                .collect::<Vec<_>>();
+            asset_paths.sort();
+
+            for asset_path in asset_paths {
+                let meta_path = PathBuf::from(format!("{}.meta", asset_path.display()));
+                if let (Some(asset_file_name), true) =
+                    (asset_path.file_name().and_then(|f| f.to_str()), meta_path.exists())
+                {
+                    let meta =
+                        read_to_string(&meta_path).with_context(|| format!("reading {}", meta_path.display()))?;
+                    let guid = meta
+                        .lines()
+                        .find_map(|line| line.strip_prefix("guid: "))
+                        .map(|guid| guid.trim().to_owned())
+                        .ok_or(ApplicationError::InvalidMetadata)
+                        .with_context(|| format!("no guid in {}", meta_path.display()))?;
+
+                    let pathname = format!("{}/{}/{}", unity_asset_path, material_name, asset_file_name);
+                    let asset = read(&asset_path).with_context(|| format!("reading {}", asset_path.display()))?;
+                    append_package_entry(&mut package, &format!("{}/asset", guid), &asset)
+                        .and_then(|_| {
+                            append_package_entry(&mut package, &format!("{}/asset.meta", guid), meta.as_bytes())
+                        })
+                        .and_then(|_| {
+                            append_package_entry(&mut package, &format!("{}/pathname", guid), pathname.as_bytes())
+                        })
+                        .with_context(|| format!("writing {}", temp_file_path.display()))?;
+                }
+            }
+
+            progress.inc(material_name);
+        }
     }
+
+    package
+        .into_inner()
+        .and_then(|f| f.finish())
+        .with_context(|| format!("writing {}", temp_file_path.display()))?;
+    progress.finish();
+    rename(&temp_file_path, unity_package_path).with_context(|| format!("writing {}", unity_package_path.display()))?;
+
+    Ok(())
+}
+
+fn append_package_entry<W: Write>(
+    package: &mut tar::Builder<W>,
+    entry_path: &str,
+    entry_data: &[u8],
+) -> std::io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(entry_data.len() as _);
+    header.set_mode(0o644);
+    header.set_cksum();
+    package.append_data(&mut header, entry_path, entry_data)
+}
+
+/// GUIDs are derived from the asset file name, so re-exported assets keep their references in Unity projects.
+fn asset_guid(asset_file_name: &str) -> String {
+    Uuid::new_v5(
+        &Uuid::NAMESPACE_URL,
+        format!("mtld:unity:{}", asset_file_name).as_bytes(),
+    )
+    .simple()
+    .to_string()
+}
+
+/// Texture arrays have their own GUID namespace, so an array named like a material texture doesn't share its GUID.
+fn array_asset_guid(texture_file_name: &str) -> String {
+    Uuid::new_v5(
+        &Uuid::NAMESPACE_URL,
+        format!("mtld:unity-array:{}", texture_file_name).as_bytes(),
+    )
+    .simple()
+    .to_string()
 }
@@ -0,0 +1,110 @@
+// Copyright (c) 2021 Kyrylo Bazhenov
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::path::*;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::Duration;
+
+use crate::application_error::*;
+
+/// Watches source folders and calls `rebuild` with the names of materials whose sources changed.
+///
+/// Changes are collected until no change happens for `debounce`, so saving many textures at once rebuilds every
+/// material only once. Errors of `rebuild` are logged and watching continues.
+pub fn watch(
+    source_paths: &[PathBuf],
+    debounce: Duration,
+    mut rebuild: impl FnMut(&BTreeSet<String>) -> Result<(), ApplicationError>,
+) -> Result<(), ApplicationError> {
+    let (sender, receiver) = channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+
+    // Event paths are absolute, source folders are matched against them by their canonical path.
+    let mut watched_paths = Vec::new();
+    for source_path in source_paths {
+        if !source_path.is_dir() {
+            log::warn!("{} doesn't exist and is not watched", source_path.display());
+            continue;
+        }
+
+        watcher
+            .watch(source_path, RecursiveMode::Recursive)
+            .with_context(|| format!("watching {}", source_path.display()))?;
+        watched_paths.push(
+            source_path
+                .canonicalize()
+                .with_context(|| format!("reading {}", source_path.display()))?,
+        );
+    }
+
+    log::info!("WATCH {} folders", watched_paths.len());
+
+    let mut changed_materials = BTreeSet::new();
+    loop {
+        // Waits for the first change without a timeout, then for the rest of the burst.
+        let event = if changed_materials.is_empty() {
+            receiver.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else {
+            receiver.recv_timeout(debounce)
+        };
+
+        match event {
+            Ok(Ok(event)) => {
+                // Reading sources while packing them must not trigger another rebuild.
+                if matches!(event.kind, EventKind::Access(_)) {
+                    continue;
+                }
+
+                for event_path in &event.paths {
+                    if let Some(material_name) = changed_material(&watched_paths, event_path) {
+                        log::debug!("CHANGED {}", event_path.display());
+                        changed_materials.insert(material_name);
+                    }
+                }
+            }
+            Ok(Err(err)) => log::warn!("{}", err),
+            Err(RecvTimeoutError::Timeout) => {
+                log::info!(
+                    "REBUILD {}",
+                    changed_materials.iter().cloned().collect::<Vec<_>>().join(", ")
+                );
+                if let Err(err) = rebuild(&changed_materials) {
+                    log::error!("{}", err.chain());
+                }
+                changed_materials.clear();
+            }
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+/// Returns the name of the material a changed file belongs to: the material folder or archive directly in a source
+/// folder, or the download metadata in its `.mtld` folder.
+fn changed_material(watched_paths: &[PathBuf], event_path: &Path) -> Option<String> {
+    let relative_path = watched_paths
+        .iter()
+        .find_map(|watched_path| event_path.strip_prefix(watched_path).ok())?;
+
+    let mut components = relative_path.iter().map(Path::new);
+    let first_component = components.next()?;
+    let file_name = first_component.to_string_lossy();
+    if file_name == ".mtld" {
+        let metadata_path = components.next()?;
+        return Some(metadata_path.file_stem()?.to_string_lossy().into_owned());
+    }
+
+    // Build manifests, listings and temporary files are written by mtld itself.
+    if file_name.starts_with('.') || file_name.ends_with(".tmp") {
+        return None;
+    }
+
+    match first_component.extension() {
+        Some(extension) if extension == "zip" => Some(first_component.file_stem()?.to_string_lossy().into_owned()),
+        Some(_) if components.next().is_none() => None,
+        _ => Some(file_name.into_owned()),
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright (c) 2021 Kyrylo Bazhenov
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use image::*;
+use itertools::izip;
+
+use crate::color::{linear_to_srgb, srgb_to_linear};
+
+/// Reflectance of dielectrics at normal incidence, the specular color of every non-metal in the metal/rough workflow.
+const DIELECTRIC_SPECULAR: f32 = 0.04;
+
+/// Metal/rough maps converted from spec/gloss maps.
+pub struct MetalRoughImages {
+    /// Base color in RGB, with the diffuse alpha.
+    pub albedo: RgbaImage,
+    pub metalness: Option<GrayImage>,
+    pub roughness: Option<GrayImage>,
+}
+
+/// Spec/gloss maps converted from metal/rough maps.
+pub struct SpecGlossImages {
+    /// Diffuse color in RGB, with the albedo alpha.
+    pub diffuse: RgbaImage,
+    /// Specular color in RGB, glossiness in A.
+    pub specular_glossiness: RgbaImage,
+}
+
+/// Converts spec/gloss maps to metal/rough with the energy-conserving approximation of the glTF spec/gloss extension
+/// converter: metalness is solved from the brightness of diffuse and specular, then the base color blends the colors
+/// that explain diffuse and specular. Without a specular map the material is a dielectric.
+pub fn metal_rough_from_spec_gloss(
+    diffuse: &RgbaImage,
+    specular: Option<&RgbImage>,
+    glossiness: Option<&GrayImage>,
+) -> MetalRoughImages {
+    let mut albedo = RgbaImage::new(diffuse.width(), diffuse.height());
+    let mut metalness = GrayImage::new(diffuse.width(), diffuse.height());
+    for (x, y, albedo) in albedo.enumerate_pixels_mut() {
+        let diffuse_pixel = diffuse.get_pixel(x, y);
+        albedo[3] = diffuse_pixel[3];
+        let diffuse = diffuse_pixel.to_rgb().0.map(|f| srgb_to_linear(f as f32 / 255.0));
+        let specular = specular.map_or([DIELECTRIC_SPECULAR; 3], |f| {
+            f.get_pixel(x, y).0.map(|f| srgb_to_linear(f as f32 / 255.0))
+        });
+
+        let one_minus_specular_strength = 1.0 - specular.iter().cloned().fold(0.0, f32::max);
+        let metallic = solve_metallic(
+            perceived_brightness(diffuse),
+            perceived_brightness(specular),
+            one_minus_specular_strength,
+        );
+
+        let base_color = izip!(diffuse, specular).map(|(diffuse, specular)| {
+            let from_diffuse =
+                diffuse * one_minus_specular_strength / (1.0 - DIELECTRIC_SPECULAR) / (1.0 - metallic).max(1e-4);
+            let from_specular = (specular - DIELECTRIC_SPECULAR * (1.0 - metallic)) / metallic.max(1e-4);
+            let blend = metallic * metallic;
+            (from_diffuse * (1.0 - blend) + from_specular * blend).clamp(0.0, 1.0)
+        });
+
+        for (target, value) in izip!(albedo.0.iter_mut(), base_color) {
+            *target = to_byte(linear_to_srgb(value));
+        }
+        metalness.put_pixel(x, y, Luma([to_byte(metallic)]));
+    }
+
+    MetalRoughImages {
+        albedo,
+        metalness: specular.map(|_| metalness),
+        roughness: glossiness.map(|glossiness| {
+            GrayImage::from_fn(glossiness.width(), glossiness.height(), |x, y| {
+                Luma([255 - glossiness.get_pixel(x, y)[0]])
+            })
+        }),
+    }
+}
+
+/// Converts metal/rough maps to spec/gloss: metals move their albedo into the specular color and get a black diffuse
+/// color, dielectrics keep their albedo as diffuse color with a 4% specular color.
+pub fn spec_gloss_from_metal_rough(albedo: &RgbaImage, material_pack: &RgbaImage) -> SpecGlossImages {
+    let mut diffuse = RgbaImage::new(albedo.width(), albedo.height());
+    let mut specular_glossiness = RgbaImage::new(albedo.width(), albedo.height());
+    for (diffuse, specular_glossiness, albedo, material_pack) in izip!(
+        diffuse.pixels_mut(),
+        specular_glossiness.pixels_mut(),
+        albedo.pixels(),
+        material_pack.pixels()
+    ) {
+        let metalness = material_pack[0] as f32 / 255.0;
+        for channel in 0..3 {
+            let albedo = srgb_to_linear(albedo[channel] as f32 / 255.0);
+            diffuse[channel] = to_byte(linear_to_srgb(albedo * (1.0 - metalness)));
+            specular_glossiness[channel] = to_byte(linear_to_srgb(
+                DIELECTRIC_SPECULAR * (1.0 - metalness) + albedo * metalness,
+            ));
+        }
+        diffuse[3] = albedo[3];
+        specular_glossiness[3] = 255 - material_pack[3];
+    }
+
+    SpecGlossImages {
+        diffuse,
+        specular_glossiness,
+    }
+}
+
+/// Solves the metalness that produces the given diffuse and specular brightness, a quadratic in the metalness.
+fn solve_metallic(diffuse: f32, specular: f32, one_minus_specular_strength: f32) -> f32 {
+    if specular < DIELECTRIC_SPECULAR {
+        return 0.0;
+    }
+
+    let a = DIELECTRIC_SPECULAR;
+    let b = diffuse * one_minus_specular_strength / (1.0 - DIELECTRIC_SPECULAR) + specular - 2.0 * DIELECTRIC_SPECULAR;
+    let c = DIELECTRIC_SPECULAR - specular;
+    let discriminant = (b * b - 4.0 * a * c).max(0.0);
+    ((-b + discriminant.sqrt()) / (2.0 * a)).clamp(0.0, 1.0)
+}
+
+fn perceived_brightness(color: [f32; 3]) -> f32 {
+    (0.299 * color[0] * color[0] + 0.587 * color[1] * color[1] + 0.114 * color[2] * color[2]).sqrt()
+}
+
+fn to_byte(value: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}